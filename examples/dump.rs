@@ -17,6 +17,7 @@ fn main() {
     let mut output = fs::OpenOptions::new()
         .write(true)
         .create(true)
+        .truncate(true)
         .open("output.dtb")
         .unwrap();
     output.write_all(&dtb).unwrap();
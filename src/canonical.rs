@@ -0,0 +1,550 @@
+//! Canonical form of trees
+//!
+//! Tools emit nodes and properties in different orders and number phandles
+//! differently, so equivalent trees can produce different blobs. `sort()`
+//! orders children by base name and numeric unit address and properties by
+//! name; `renumber_phandles()` numbers the nodes with a phandle in traversal
+//! order and rewrites all references. `canonicalize()` does both and drops
+//! the layout of the loaded blob, so that equivalent trees store to the same
+//! bytes.
+//!
+//! References are only found in properties of well-known bindings (phandle
+//! lists such as `clocks` or `*-gpios`, plain phandles such as
+//! `interrupt-parent` or `*-supply`, and maps such as `interrupt-map` or
+//! `msi-map`). Phandles in other properties are left unchanged.
+
+use core::cmp::Ordering;
+
+use phandle::PhandleError;
+use {split_name, DeviceTree, Node};
+
+/// Phandle lists whose entries take the number of argument cells given by
+/// a property of the referenced node.
+const ARGS_LISTS: &[(&str, &str)] = &[
+    ("assigned-clock-parents", "#clock-cells"),
+    ("assigned-clocks", "#clock-cells"),
+    ("clocks", "#clock-cells"),
+    ("cooling-device", "#cooling-cells"),
+    ("dmas", "#dma-cells"),
+    ("hwlocks", "#hwlock-cells"),
+    ("interconnects", "#interconnect-cells"),
+    ("interrupts-extended", "#interrupt-cells"),
+    ("io-channels", "#io-channel-cells"),
+    ("iommus", "#iommu-cells"),
+    ("mboxes", "#mbox-cells"),
+    ("phys", "#phy-cells"),
+    ("power-domains", "#power-domain-cells"),
+    ("pwms", "#pwm-cells"),
+    ("resets", "#reset-cells"),
+    ("sound-dai", "#sound-dai-cells"),
+    ("thermal-sensors", "#thermal-sensor-cells"),
+];
+
+/// Children of the root whose subtrees hold paths or fixup data in
+/// properties named after labels or other properties, which can clash with
+/// the names of bindings.
+const STRING_NODES: &[&str] = &["aliases", "__symbols__", "__fixups__", "__local_fixups__"];
+
+/// Properties consisting of phandles only.
+const PLAIN_LISTS: &[&str] = &[
+    "backlight",
+    "cpu",
+    "extcon",
+    "firmware",
+    "interrupt-parent",
+    "memory-region",
+    "mmc-pwrseq",
+    "nvmem-cells",
+    "phy-handle",
+    "remote-endpoint",
+];
+
+/// How the phandles of a property are laid out.
+enum References {
+    /// Every cell is a phandle.
+    Plain,
+
+    /// Phandles followed by the number of argument cells given by property
+    /// `cells` of the referenced node, which may be absent if `optional`.
+    Args { cells: String, optional: bool },
+
+    /// Entries of `stride` cells with the phandle at `index`.
+    Fixed { stride: usize, index: usize },
+
+    /// A nexus map like `interrupt-map` or `gpio-map`, with this stem.
+    Map(String),
+}
+
+/// A phandle inside a property, located by the pre-order index of its node,
+/// the index of the property and the index of the cell.
+struct Reference {
+    node: usize,
+    prop: usize,
+    cell: usize,
+    phandle: u32,
+}
+
+impl DeviceTree {
+    /// Bring the tree into its canonical form: sort it, optionally renumber
+    /// its phandles and drop the layout of the loaded blob.
+    pub fn canonicalize(&mut self, renumber_phandles: bool) -> Result<(), PhandleError> {
+        self.sort();
+        if renumber_phandles {
+            self.renumber_phandles()?;
+        }
+        self.clear_layout();
+        Ok(())
+    }
+
+    /// Sort all children and properties, see `Node::sort`.
+    pub fn sort(&mut self) {
+        self.root.sort();
+    }
+
+    /// Number the nodes with a phandle 1, 2, ... in pre-order and update
+    /// all references to them.
+    ///
+    /// Fails without changing the tree if a reference does not point to a
+    /// node or cannot be decoded.
+    pub fn renumber_phandles(&mut self) -> Result<(), PhandleError> {
+        let mut numbers: Vec<(u32, u32)> = self
+            .nodes()
+            .filter_map(|(_, node)| node.phandle())
+            .enumerate()
+            .map(|(i, phandle)| (phandle, i as u32 + 1))
+            .collect();
+        // a duplicate phandle refers to its first node
+        numbers.sort_by_key(|&(old, _)| old);
+        numbers.dedup_by_key(|&mut (old, _)| old);
+
+        let mut updates = Vec::new();
+        for reference in self.references()? {
+            let new = numbers
+                .binary_search_by_key(&reference.phandle, |&(old, _)| old)
+                .map_err(|_| PhandleError::InvalidPhandle(reference.phandle))?;
+            updates.push((reference, numbers[new].1));
+        }
+
+        let mut updates = updates.into_iter().peekable();
+        let mut ordinal = 0;
+        let mut next_phandle = 1;
+        self.walk_mut(&mut |_, node| {
+            if node.phandle().is_some() {
+                for name in ["phandle", "linux,phandle"].iter() {
+                    if node.has_prop(name) {
+                        node.set_prop_u32(name, next_phandle);
+                    }
+                }
+                next_phandle += 1;
+            }
+
+            while let Some((reference, _)) = updates.peek() {
+                if reference.node != ordinal {
+                    break;
+                }
+                let (reference, new) = updates.next().unwrap();
+                let cell = reference.cell * 4;
+                node.props[reference.prop].1[cell..(cell + 4)].copy_from_slice(&new.to_be_bytes());
+            }
+            ordinal += 1;
+        });
+        Ok(())
+    }
+
+    /// All phandles referenced by the tree, in pre-order.
+    fn references(&self) -> Result<Vec<Reference>, PhandleError> {
+        // like `find_phandle`, a duplicate phandle refers to its first node
+        let mut targets: Vec<(u32, &Node)> = self
+            .nodes()
+            .filter_map(|(_, node)| node.phandle().map(|phandle| (phandle, node)))
+            .collect();
+        targets.sort_by_key(|&(phandle, _)| phandle);
+        targets.dedup_by_key(|&mut (phandle, _)| phandle);
+        let find = |phandle| {
+            targets
+                .binary_search_by_key(&phandle, |&(phandle, _)| phandle)
+                .map(|i| targets[i].1)
+                .map_err(|_| PhandleError::InvalidPhandle(phandle))
+        };
+
+        let mut references = Vec::new();
+        let mut skip_below = None;
+
+        for (ordinal, (depth, node)) in self.nodes().enumerate() {
+            match skip_below {
+                Some(skip) if depth > skip => continue,
+                _ if depth == 1 && STRING_NODES.contains(&node.name.as_str()) => {
+                    skip_below = Some(depth);
+                    continue;
+                }
+                _ => skip_below = None,
+            }
+
+            for (index, (name, _)) in node.props.iter().enumerate() {
+                let kind = match references_in(node, name) {
+                    Some(kind) => kind,
+                    None => continue,
+                };
+
+                let mut add = |cell, phandle| {
+                    references.push(Reference {
+                        node: ordinal,
+                        prop: index,
+                        cell,
+                        phandle,
+                    })
+                };
+                let cells = node.prop_cells(name)?;
+
+                match kind {
+                    References::Plain => {
+                        for (cell, &phandle) in cells.iter().enumerate() {
+                            if phandle != 0 {
+                                add(cell, phandle);
+                            }
+                        }
+                    }
+                    References::Fixed { stride, index } => {
+                        if cells.len() % stride != 0 {
+                            return Err(PhandleError::Truncated);
+                        }
+                        for cell in (index..cells.len()).step_by(stride) {
+                            add(cell, cells[cell]);
+                        }
+                    }
+                    References::Args {
+                        cells: cells_name,
+                        optional,
+                    } => {
+                        let mut cell = 0;
+                        while cell < cells.len() {
+                            let phandle = cells[cell];
+                            cell += 1;
+                            if phandle == 0 {
+                                continue;
+                            }
+
+                            let target = find(phandle)?;
+                            let count = match target.prop_u32(&cells_name) {
+                                Ok(count) => count as usize,
+                                Err(_) if optional => 0,
+                                Err(_) => return Err(PhandleError::MissingCells),
+                            };
+                            add(cell - 1, phandle);
+                            cell += count;
+                        }
+                        if cell > cells.len() {
+                            return Err(PhandleError::Truncated);
+                        }
+                    }
+                    References::Map(stem) => {
+                        let cells_name = format!("#{}-cells", stem);
+                        let interrupt = stem == "interrupt";
+                        let mut child = node.prop_u32(&cells_name)? as usize;
+                        if interrupt {
+                            child += self.interrupt_address_cells(node) as usize;
+                        }
+
+                        let mut cell = child;
+                        while cell < cells.len() {
+                            let phandle = cells[cell];
+                            let parent = find(phandle)?;
+                            let mut size = parent
+                                .prop_u32(&cells_name)
+                                .map_err(|_| PhandleError::MissingCells)?
+                                as usize;
+                            if interrupt {
+                                size += parent.prop_u32("#address-cells").unwrap_or(0) as usize;
+                            }
+
+                            add(cell, phandle);
+                            cell += 1 + size + child;
+                        }
+                        if cell != cells.len() + child {
+                            return Err(PhandleError::Truncated);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(references)
+    }
+}
+
+impl Node {
+    /// Sort the children of this node and its descendants with
+    /// `compare_names`, and their properties by name.
+    pub fn sort(&mut self) {
+        self.props.sort_by(|a, b| a.0.cmp(&b.0));
+        self.children
+            .sort_by(|a, b| compare_names(&a.name, &b.name));
+        for child in self.children.iter_mut() {
+            child.sort();
+        }
+    }
+}
+
+/// Compare node names by base name, then by unit address.
+///
+/// Nodes without a unit address come first. Unit addresses are compared
+/// by their comma-separated components, numerically if they are hex
+/// numbers, so `uart@9` sorts before `uart@10`. Components that are not
+/// numbers sort after all that are, by their text.
+pub fn compare_names(a: &str, b: &str) -> Ordering {
+    let (a_base, a_address) = split_name(a);
+    let (b_base, b_address) = split_name(b);
+
+    a_base
+        .cmp(b_base)
+        .then_with(|| match (a_address, b_address) {
+            (Some(a), Some(b)) => compare_unit_addresses(a, b),
+            (a, b) => a.cmp(&b),
+        })
+}
+
+fn compare_unit_addresses(a: &str, b: &str) -> Ordering {
+    let mut a_parts = a.split(',');
+    let mut b_parts = b.split(',');
+
+    loop {
+        let (a_part, b_part) = match (a_parts.next(), b_parts.next()) {
+            (Some(a_part), Some(b_part)) => (a_part, b_part),
+            (a_part, b_part) => return a_part.cmp(&b_part).then_with(|| a.cmp(b)),
+        };
+
+        // a total order even when numbers and text are mixed
+        let ord = match (
+            u64::from_str_radix(a_part, 16),
+            u64::from_str_radix(b_part, 16),
+        ) {
+            (Ok(a_val), Ok(b_val)) => a_val.cmp(&b_val),
+            (Ok(_), Err(_)) => Ordering::Less,
+            (Err(_), Ok(_)) => Ordering::Greater,
+            (Err(_), Err(_)) => a_part.cmp(b_part),
+        };
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+}
+
+/// How property `name` of `node` references other nodes, if it does.
+fn references_in(node: &Node, name: &str) -> Option<References> {
+    if let Some(&(_, cells)) = ARGS_LISTS.iter().find(|&&(list, _)| list == name) {
+        return Some(References::Args {
+            cells: cells.to_owned(),
+            optional: false,
+        });
+    }
+    if PLAIN_LISTS.contains(&name) || name.ends_with("-supply") {
+        return Some(References::Plain);
+    }
+    if let Some(index) = name.strip_prefix("pinctrl-") {
+        if index.bytes().all(|b| b.is_ascii_digit()) {
+            return Some(References::Plain);
+        }
+    }
+
+    match name {
+        "gpio-ranges" => {
+            return Some(References::Fixed {
+                stride: 4,
+                index: 0,
+            })
+        }
+        "msi-map" | "iommu-map" => {
+            return Some(References::Fixed {
+                stride: 4,
+                index: 1,
+            })
+        }
+        "msi-parent" => {
+            return Some(References::Args {
+                cells: "#msi-cells".to_owned(),
+                optional: true,
+            })
+        }
+        _ => (),
+    }
+
+    // hogs list lines of their own controller, not phandles
+    let gpios =
+        name == "gpios" || name == "gpio" || name.ends_with("-gpios") || name.ends_with("-gpio");
+    if gpios && !node.has_prop("gpio-hog") {
+        return Some(References::Args {
+            cells: "#gpio-cells".to_owned(),
+            optional: false,
+        });
+    }
+
+    let stem = name.strip_suffix("-map")?;
+    if node.has_prop(&format!("#{}-cells", stem)) {
+        return Some(References::Map(stem.to_owned()));
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testutil::{cells, node, rpi, tree};
+
+    fn reverse(node: &mut Node) {
+        node.props.reverse();
+        node.children.reverse();
+        for child in node.children.iter_mut() {
+            reverse(child);
+        }
+    }
+
+    fn target<'a>(dt: &'a DeviceTree, path: &str, prop: &str) -> &'a str {
+        let phandle = dt.find(path).unwrap().prop_cells(prop).unwrap()[0];
+        &dt.find_phandle(phandle).unwrap().name
+    }
+
+    #[test]
+    fn name_order() {
+        let mut names = vec![
+            "uart@10",
+            "memory@0,100",
+            "uart",
+            "memory@0,20",
+            "cpus",
+            "uart@9",
+            "memory@1",
+            "bus@zz",
+            "bus@a",
+        ];
+        names.sort_by(|a, b| compare_names(a, b));
+        assert_eq!(
+            names,
+            vec![
+                "bus@a",
+                "bus@zz",
+                "cpus",
+                "memory@0,20",
+                "memory@0,100",
+                "memory@1",
+                "uart",
+                "uart@9",
+                "uart@10",
+            ]
+        );
+
+        // mixing numbers and text must not make the result depend on the
+        // input order
+        let permutations = [
+            ["x@2", "x@10", "x@1z"],
+            ["x@10", "x@1z", "x@2"],
+            ["x@1z", "x@2", "x@10"],
+            ["x@1z", "x@10", "x@2"],
+            ["x@2", "x@1z", "x@10"],
+            ["x@10", "x@2", "x@1z"],
+        ];
+        for names in permutations.iter() {
+            let mut names = names.to_vec();
+            names.sort_by(|a, b| compare_names(a, b));
+            assert_eq!(names, vec!["x@2", "x@10", "x@1z"]);
+        }
+    }
+
+    #[test]
+    fn canonical_blobs() {
+        let mut a = rpi();
+        let mut b = rpi();
+        reverse(&mut b.root);
+        assert!(a.store().unwrap() != b.store().unwrap());
+
+        a.canonicalize(true).unwrap();
+        b.canonicalize(true).unwrap();
+        assert!(a.layout().is_none());
+        assert_eq!(a.store().unwrap(), b.store().unwrap());
+    }
+
+    #[test]
+    fn renumber_rpi() {
+        let mut dt = rpi();
+        dt.renumber_phandles().unwrap();
+
+        let phandles: Vec<u32> = dt.nodes().filter_map(|(_, n)| n.phandle()).collect();
+        assert_eq!(
+            phandles,
+            (1..(phandles.len() as u32 + 1)).collect::<Vec<_>>()
+        );
+
+        let original = rpi();
+        for &(path, prop) in [
+            ("/", "interrupt-parent"),
+            ("/soc/uart@7e201000", "clocks"),
+            ("/soc/leds/act", "gpios"),
+            ("/soc/fb", "firmware"),
+            ("/soc/i2c@7e205000", "pinctrl-0"),
+        ]
+        .iter()
+        {
+            assert_eq!(target(&dt, path, prop), target(&original, path, prop));
+        }
+        assert_eq!(dt.find("/__symbols__"), original.find("/__symbols__"));
+    }
+
+    #[test]
+    fn renumber_references() {
+        let mut dt = tree(node(
+            "",
+            vec![("interrupt-parent", cells(&[9]))],
+            vec![
+                node(
+                    "intc",
+                    vec![("phandle", cells(&[9])), ("#interrupt-cells", cells(&[1]))],
+                    vec![],
+                ),
+                node(
+                    "clk",
+                    vec![("phandle", cells(&[7])), ("#clock-cells", cells(&[1]))],
+                    vec![],
+                ),
+                node(
+                    "dev",
+                    vec![
+                        ("clocks", cells(&[7, 1, 7, 9])),
+                        ("vdd-supply", cells(&[9])),
+                        ("label", cells(&[7])),
+                    ],
+                    // only /aliases holds paths
+                    vec![node(
+                        "aliases",
+                        vec![("interrupt-parent", cells(&[9]))],
+                        vec![],
+                    )],
+                ),
+            ],
+        ));
+        dt.renumber_phandles().unwrap();
+
+        let dev = dt.find("/dev").unwrap();
+        assert_eq!(dt.root.prop_cells("interrupt-parent").unwrap(), vec![1]);
+        assert_eq!(dt.find("/clk").unwrap().phandle(), Some(2));
+        assert_eq!(dev.prop_cells("clocks").unwrap(), vec![2, 1, 2, 9]);
+        assert_eq!(dev.prop_cells("vdd-supply").unwrap(), vec![1]);
+        assert_eq!(dev.prop_cells("label").unwrap(), vec![7]);
+        let aliases = dev.child("aliases").unwrap();
+        assert_eq!(aliases.prop_cells("interrupt-parent").unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn dangling_reference() {
+        let build = || {
+            tree(node(
+                "",
+                vec![("phandle", cells(&[5]))],
+                vec![node("dev", vec![("clocks", cells(&[6]))], vec![])],
+            ))
+        };
+        let mut dt = build();
+        match dt.renumber_phandles() {
+            Err(PhandleError::InvalidPhandle(6)) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(dt, build());
+    }
+}
@@ -0,0 +1,210 @@
+//! The `/chosen` node
+//!
+//! `/chosen` does not describe hardware, but carries parameters handed from
+//! the bootloader to the operating system: the kernel command line, the
+//! console to use, the location of the initial ramdisk and seeds for
+//! randomization.
+
+use {DeviceTree, Node};
+
+/// Read-only view of the `/chosen` node.
+pub struct Chosen<'a> {
+    node: &'a Node,
+}
+
+/// Modifiable view of the `/chosen` node, see `DeviceTree::chosen_mut`.
+pub struct ChosenMut<'a> {
+    node: &'a mut Node,
+    wide: bool,
+}
+
+/// The decoded `stdout-path` property.
+#[derive(Debug, PartialEq)]
+pub struct StdoutPath<'a> {
+    /// Path or alias of the console device.
+    pub path: &'a str,
+
+    /// Options following the `:`, such as `115200n8`.
+    pub options: Option<&'a str>,
+}
+
+impl<'a> Chosen<'a> {
+    /// The underlying `/chosen` node.
+    pub fn node(&self) -> &'a Node {
+        self.node
+    }
+
+    /// The kernel command line.
+    pub fn bootargs(&self) -> Option<&'a str> {
+        self.node.prop_str("bootargs").ok()
+    }
+
+    /// The console device, from `stdout-path` or the older
+    /// `linux,stdout-path`.
+    pub fn stdout_path(&self) -> Option<StdoutPath<'a>> {
+        let raw = self
+            .node
+            .prop_str("stdout-path")
+            .or_else(|_| self.node.prop_str("linux,stdout-path"))
+            .ok()?;
+
+        Some(match raw.find(':') {
+            Some(idx) => StdoutPath {
+                path: &raw[..idx],
+                options: Some(&raw[(idx + 1)..]),
+            },
+            None => StdoutPath {
+                path: raw,
+                options: None,
+            },
+        })
+    }
+
+    /// Start and end address of the initial ramdisk.
+    ///
+    /// Both values may be stored as 32 or 64 bit integers.
+    pub fn initrd(&self) -> Option<(u64, u64)> {
+        let start = self.node.prop_uint("linux,initrd-start").ok()?;
+        let end = self.node.prop_uint("linux,initrd-end").ok()?;
+        Some((start, end))
+    }
+
+    /// Seed for kernel address space layout randomization.
+    pub fn kaslr_seed(&self) -> Option<u64> {
+        self.node.prop_u64("kaslr-seed").ok()
+    }
+
+    /// Entropy for seeding the kernel's random number generator.
+    pub fn rng_seed(&self) -> Option<&'a [u8]> {
+        self.node.prop_raw("rng-seed").map(|v| v.as_slice())
+    }
+}
+
+impl<'a> ChosenMut<'a> {
+    /// The underlying `/chosen` node.
+    pub fn node(&mut self) -> &mut Node {
+        self.node
+    }
+
+    pub fn set_bootargs(&mut self, bootargs: &str) {
+        self.node.set_prop_str("bootargs", bootargs);
+    }
+
+    /// Set `stdout-path` to `path`, followed by `:options` if given.
+    pub fn set_stdout_path(&mut self, path: &str, options: Option<&str>) {
+        match options {
+            Some(options) => self
+                .node
+                .set_prop_str("stdout-path", &format!("{}:{}", path, options)),
+            None => self.node.set_prop_str("stdout-path", path),
+        }
+    }
+
+    /// Set the initial ramdisk location.
+    ///
+    /// Addresses are stored as 64 bit values if the root node has
+    /// `#address-cells` of 2 or more, or if they do not fit in 32 bits.
+    pub fn set_initrd(&mut self, start: u64, end: u64) {
+        if self.wide || end > u64::from(u32::MAX) {
+            self.node.set_prop_u64("linux,initrd-start", start);
+            self.node.set_prop_u64("linux,initrd-end", end);
+        } else {
+            self.node.set_prop_u32("linux,initrd-start", start as u32);
+            self.node.set_prop_u32("linux,initrd-end", end as u32);
+        }
+    }
+
+    /// Remove the initial ramdisk location.
+    pub fn remove_initrd(&mut self) {
+        self.node.remove_prop("linux,initrd-start");
+        self.node.remove_prop("linux,initrd-end");
+    }
+
+    pub fn set_kaslr_seed(&mut self, seed: u64) {
+        self.node.set_prop_u64("kaslr-seed", seed);
+    }
+
+    pub fn set_rng_seed(&mut self, seed: &[u8]) {
+        self.node.set_prop("rng-seed", seed.to_vec());
+    }
+}
+
+impl DeviceTree {
+    /// The `/chosen` node, if present.
+    pub fn chosen(&self) -> Option<Chosen<'_>> {
+        self.root.find("chosen").map(|node| Chosen { node })
+    }
+
+    /// The `/chosen` node for modification. It is created if missing.
+    pub fn chosen_mut(&mut self) -> ChosenMut<'_> {
+        let wide = self.root.address_cells() >= 2;
+
+        if self.root.find("chosen").is_none() {
+            self.root.children.push(Node {
+                name: "chosen".to_owned(),
+                props: Vec::new(),
+                children: Vec::new(),
+            });
+        }
+
+        ChosenMut {
+            node: self.root.find_mut("chosen").unwrap(),
+            wide,
+        }
+    }
+
+    /// The console device node, resolved from `stdout-path`, which may name
+    /// an alias.
+    pub fn stdout(&self) -> Option<&Node> {
+        let path = self.chosen()?.stdout_path()?.path;
+        self.find(path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testutil::rpi;
+
+    #[test]
+    fn alias_paths() {
+        let dt = rpi();
+
+        assert_eq!(dt.alias("uart0"), Some("/soc/uart@7e201000"));
+        assert_eq!(dt.find("uart0").unwrap().name, "uart@7e201000");
+        assert_eq!(dt.find("gpio/i2c0").unwrap().name, "i2c0");
+        assert!(dt.find("nonexistent").is_none());
+    }
+
+    #[test]
+    fn update_chosen() {
+        let mut dt = rpi();
+        assert_eq!(dt.chosen().unwrap().bootargs(), Some(""));
+
+        {
+            let mut chosen = dt.chosen_mut();
+            chosen.set_bootargs("console=ttyAMA0");
+            chosen.set_stdout_path("uart0", Some("115200n8"));
+            chosen.set_initrd(0x0200_0000, 0x0280_0000);
+            chosen.set_kaslr_seed(0x1234_5678_9abc_def0);
+        }
+
+        let dt = DeviceTree::load(&dt.store().unwrap()).unwrap();
+        let chosen = dt.chosen().unwrap();
+        assert_eq!(chosen.bootargs(), Some("console=ttyAMA0"));
+        assert_eq!(
+            chosen.stdout_path(),
+            Some(StdoutPath {
+                path: "uart0",
+                options: Some("115200n8"),
+            })
+        );
+        assert_eq!(
+            chosen.node().prop_raw("linux,initrd-start").unwrap().len(),
+            4
+        );
+        assert_eq!(chosen.initrd(), Some((0x0200_0000, 0x0280_0000)));
+        assert_eq!(chosen.kaslr_seed(), Some(0x1234_5678_9abc_def0));
+        assert_eq!(dt.stdout().unwrap().name, "uart@7e201000");
+    }
+}
@@ -0,0 +1,435 @@
+//! Clock tree
+//!
+//! Clock providers are nodes with `#clock-cells`. Consumers reference them
+//! through the phandle list `clocks`, optionally naming each input with
+//! `clock-names`. Providers can be consumers themselves, which is how the
+//! parent/child relations of the clock tree are expressed.
+//!
+//! `assigned-clocks`, `assigned-clock-parents` and `assigned-clock-rates`
+//! request a configuration to be applied when the device is probed. Rates are
+//! only known for the generic `fixed-clock` and `fixed-factor-clock`
+//! bindings; everything else needs a driver.
+
+use phandle::{PhandleArgs, PhandleError};
+use {DeviceTree, Node};
+
+/// A named clock input of a consumer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClockInput<'a> {
+    /// The name from `clock-names`, if any.
+    pub name: Option<&'a str>,
+
+    /// The provider and clock specifier.
+    pub clock: PhandleArgs<'a>,
+}
+
+/// A clock configuration requested through `assigned-clocks`.
+#[derive(Debug, PartialEq)]
+pub struct AssignedClock<'a> {
+    pub clock: PhandleArgs<'a>,
+
+    /// The parent to switch the clock to, from `assigned-clock-parents`.
+    pub parent: Option<PhandleArgs<'a>>,
+
+    /// The rate to set in Hz, from `assigned-clock-rates`.
+    pub rate: Option<u32>,
+}
+
+/// The generic bindings whose rates can be computed from the tree.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClockKind {
+    /// A `fixed-clock` running at `rate` Hz.
+    Fixed { rate: u64 },
+
+    /// A `fixed-factor-clock`, running at the rate of its parent multiplied
+    /// by `mult` and divided by `div`.
+    FixedFactor { mult: u32, div: u32 },
+
+    /// Any other clock provider.
+    Other,
+}
+
+/// A node with `#clock-cells`.
+#[derive(Debug, PartialEq)]
+pub struct ClockProvider<'a> {
+    pub node: &'a Node,
+
+    /// The number of cells in a clock specifier (`#clock-cells`).
+    pub cells: u32,
+
+    /// The names of the outputs (`clock-output-names`).
+    pub output_names: Vec<&'a str>,
+
+    pub kind: ClockKind,
+
+    /// The clocks this provider is fed by.
+    pub parents: Vec<ClockInput<'a>>,
+}
+
+/// A node with a `clocks` or `assigned-clocks` property.
+#[derive(Debug, PartialEq)]
+pub struct ClockConsumer<'a> {
+    pub node: &'a Node,
+    pub inputs: Vec<ClockInput<'a>>,
+    pub assigned: Vec<AssignedClock<'a>>,
+}
+
+/// All clock providers and consumers of a tree, see
+/// `DeviceTree::clock_graph`.
+#[derive(Debug)]
+pub struct ClockGraph<'a> {
+    /// Providers, in tree order.
+    pub providers: Vec<ClockProvider<'a>>,
+
+    /// Consumers, in tree order. Providers with parents are included.
+    pub consumers: Vec<ClockConsumer<'a>>,
+
+    /// Nodes whose clock properties could not be decoded, such as a
+    /// `fixed-clock` without `clock-frequency`, in tree order. They are
+    /// neither providers nor consumers.
+    pub invalid: Vec<(&'a Node, PhandleError)>,
+}
+
+impl<'a> ClockProvider<'a> {
+    /// The name of the output selected by `args`, from
+    /// `clock-output-names`.
+    ///
+    /// Providers with no cells have a single output; providers with one cell
+    /// use it as the output index, taking `clock-indices` into account.
+    pub fn output_name(&self, args: &[u32]) -> Option<&'a str> {
+        let index = match args.first() {
+            None => 0,
+            Some(&index) => match self.node.prop_cells("clock-indices") {
+                Ok(indices) => indices.iter().position(|&i| i == index)?,
+                Err(_) => index as usize,
+            },
+        };
+        self.output_names.get(index).cloned()
+    }
+}
+
+impl<'a> ClockGraph<'a> {
+    /// The provider entry for `node`.
+    pub fn provider(&self, node: &Node) -> Option<&ClockProvider<'a>> {
+        self.providers
+            .iter()
+            .find(|p| ::core::ptr::eq(p.node, node))
+    }
+
+    /// All consumers with at least one input from `provider`.
+    pub fn consumers_of(&self, provider: &Node) -> Vec<&ClockConsumer<'a>> {
+        self.consumers
+            .iter()
+            .filter(|c| {
+                c.inputs
+                    .iter()
+                    .any(|i| ::core::ptr::eq(i.clock.node, provider))
+            })
+            .collect()
+    }
+
+    /// The rate of `clock` in Hz, if it can be derived from fixed and
+    /// fixed-factor clocks alone.
+    pub fn rate(&self, clock: &PhandleArgs) -> Option<u64> {
+        // a loop in the clock tree would otherwise recurse forever
+        self.rate_limited(clock.node, self.providers.len())
+    }
+
+    fn rate_limited(&self, node: &Node, depth: usize) -> Option<u64> {
+        let provider = self.provider(node)?;
+
+        match provider.kind {
+            ClockKind::Fixed { rate } => Some(rate),
+            ClockKind::FixedFactor { mult, div } => {
+                let parent = provider.parents.first()?;
+                let rate = self.rate_limited(parent.clock.node, depth.checked_sub(1)?)?;
+                rate.checked_mul(u64::from(mult))?
+                    .checked_div(u64::from(div))
+            }
+            ClockKind::Other => None,
+        }
+    }
+}
+
+impl Node {
+    /// The clock inputs of this node from `clocks` and `clock-names`.
+    ///
+    /// Empty entries (a phandle of `0`) are skipped. Returns an empty list if
+    /// the node has no `clocks`.
+    pub fn clock_inputs<'a>(
+        &'a self,
+        tree: &'a DeviceTree,
+    ) -> Result<Vec<ClockInput<'a>>, PhandleError> {
+        if !self.has_prop("clocks") {
+            return Ok(Vec::new());
+        }
+
+        let names = self.prop_str_list("clock-names").unwrap_or_default();
+        let mut inputs = Vec::new();
+
+        for (index, entry) in self
+            .phandle_args(tree, "clocks", "#clock-cells")?
+            .enumerate()
+        {
+            let clock = match entry {
+                Ok(clock) => clock,
+                Err(PhandleError::Empty) => continue,
+                Err(e) => return Err(e),
+            };
+            inputs.push(ClockInput {
+                name: names.get(index).cloned(),
+                clock,
+            });
+        }
+        Ok(inputs)
+    }
+
+    /// The clock input named `name` in `clock-names`.
+    pub fn clock_input<'a>(
+        &self,
+        tree: &'a DeviceTree,
+        name: &str,
+    ) -> Result<PhandleArgs<'a>, PhandleError> {
+        self.phandle_args_by_name(tree, "clocks", "#clock-cells", "clock-names", name)
+    }
+
+    /// The clock configuration requested by `assigned-clocks`,
+    /// `assigned-clock-parents` and `assigned-clock-rates`.
+    ///
+    /// Empty entries in `assigned-clocks` are skipped; empty parents and
+    /// rates of `0` mean no change and are returned as `None`.
+    pub fn assigned_clocks<'a>(
+        &self,
+        tree: &'a DeviceTree,
+    ) -> Result<Vec<AssignedClock<'a>>, PhandleError> {
+        if !self.has_prop("assigned-clocks") {
+            return Ok(Vec::new());
+        }
+
+        let mut parents: Vec<Option<PhandleArgs>> = Vec::new();
+        if self.has_prop("assigned-clock-parents") {
+            for entry in self.phandle_args(tree, "assigned-clock-parents", "#clock-cells")? {
+                match entry {
+                    Ok(parent) => parents.push(Some(parent)),
+                    Err(PhandleError::Empty) => parents.push(None),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        let rates = self.prop_cells("assigned-clock-rates").unwrap_or_default();
+
+        let mut parents = parents.into_iter();
+        let mut assigned = Vec::new();
+        for (index, entry) in self
+            .phandle_args(tree, "assigned-clocks", "#clock-cells")?
+            .enumerate()
+        {
+            let parent = parents.next().unwrap_or(None);
+            let clock = match entry {
+                Ok(clock) => clock,
+                Err(PhandleError::Empty) => continue,
+                Err(e) => return Err(e),
+            };
+            assigned.push(AssignedClock {
+                clock,
+                parent,
+                rate: rates.get(index).cloned().filter(|&r| r != 0),
+            });
+        }
+        Ok(assigned)
+    }
+}
+
+impl DeviceTree {
+    /// Collect all clock providers and consumers.
+    ///
+    /// A node with malformed clock properties does not fail the whole graph
+    /// but is reported in `ClockGraph::invalid`.
+    pub fn clock_graph(&self) -> ClockGraph<'_> {
+        let mut graph = ClockGraph {
+            providers: Vec::new(),
+            consumers: Vec::new(),
+            invalid: Vec::new(),
+        };
+
+        let devices = self.root.children.iter().filter(|n| !is_metadata(n));
+        for (_, node) in devices.flat_map(Node::pre_order) {
+            if let Err(e) = self.add_clock_node(node, &mut graph) {
+                graph.invalid.push((node, e));
+            }
+        }
+        graph
+    }
+
+    /// Add `node` to `graph` if it is a provider or consumer. Nothing is
+    /// added if decoding fails.
+    fn add_clock_node<'a>(
+        &'a self,
+        node: &'a Node,
+        graph: &mut ClockGraph<'a>,
+    ) -> Result<(), PhandleError> {
+        let inputs = node.clock_inputs(self)?;
+        let assigned = node.assigned_clocks(self)?;
+
+        let provider = match node.prop_u32("#clock-cells") {
+            Ok(cells) => {
+                let kind = if node.is_compatible("fixed-clock") {
+                    ClockKind::Fixed {
+                        rate: node.prop_uint("clock-frequency")?,
+                    }
+                } else if node.is_compatible("fixed-factor-clock") {
+                    ClockKind::FixedFactor {
+                        mult: node.prop_u32("clock-mult")?,
+                        div: node.prop_u32("clock-div")?,
+                    }
+                } else {
+                    ClockKind::Other
+                };
+
+                Some(ClockProvider {
+                    node,
+                    cells,
+                    output_names: node.prop_str_list("clock-output-names").unwrap_or_default(),
+                    kind,
+                    parents: inputs.clone(),
+                })
+            }
+            Err(_) => None,
+        };
+
+        graph.providers.extend(provider);
+        if !inputs.is_empty() || !assigned.is_empty() {
+            graph.consumers.push(ClockConsumer {
+                node,
+                inputs,
+                assigned,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Whether `node` holds paths or overlay data rather than device bindings,
+/// e.g. `/aliases` or `/__symbols__`.
+fn is_metadata(node: &Node) -> bool {
+    node.name == "aliases" || node.name.starts_with("__")
+}
+
+#[cfg(test)]
+mod test {
+    use testutil::{cells, node, rpi, tree};
+
+    #[test]
+    fn rpi_clocks() {
+        let dt = rpi();
+        let graph = dt.clock_graph();
+        assert!(graph.invalid.is_empty());
+
+        let uart = dt.find("/soc/uart@7e201000").unwrap();
+        let inputs = uart.clock_inputs(&dt).unwrap();
+        assert_eq!(inputs.len(), 2);
+        assert_eq!(inputs[1].name, Some("apb_pclk"));
+        assert_eq!(graph.rate(&inputs[0].clock), Some(3_000_000));
+
+        let apb = uart.clock_input(&dt, "apb_pclk").unwrap();
+        assert_eq!(
+            graph.provider(apb.node).unwrap().output_name(&[]),
+            Some("apb_pclk")
+        );
+
+        // clock@5 doubles the core clock
+        let factor = dt.find("/clocks/clock@5").unwrap();
+        let aux_uart = dt.find("/soc/uart@7e215040").unwrap();
+        let input = aux_uart.clock_inputs(&dt).unwrap().remove(0);
+        assert!(::core::ptr::eq(input.clock.node, factor));
+        assert_eq!(input.name, None);
+        assert_eq!(graph.rate(&input.clock), Some(500_000_000));
+
+        let core = dt.find("/clocks/clock@0").unwrap();
+        let users: Vec<&str> = graph
+            .consumers_of(core)
+            .iter()
+            .map(|c| c.node.name.as_str())
+            .collect();
+        assert!(users.contains(&"clock@5"));
+        assert!(users.contains(&"spi@7e204000"));
+    }
+
+    #[test]
+    fn assigned_clocks() {
+        let root = node(
+            "",
+            vec![],
+            vec![
+                node(
+                    "osc",
+                    vec![
+                        ("compatible", b"fixed-clock\0".to_vec()),
+                        ("#clock-cells", cells(&[0])),
+                        ("clock-frequency", cells(&[24_000_000])),
+                        ("phandle", cells(&[1])),
+                    ],
+                    vec![],
+                ),
+                node(
+                    "cru",
+                    vec![
+                        ("#clock-cells", cells(&[1])),
+                        ("clock-output-names", b"pll\0bus\0uart\0".to_vec()),
+                        ("clock-indices", cells(&[1, 5, 7])),
+                        ("clocks", cells(&[1])),
+                        ("assigned-clocks", cells(&[2, 1, 2, 7])),
+                        ("assigned-clock-parents", cells(&[0, 2, 1])),
+                        ("assigned-clock-rates", cells(&[1_200_000_000, 0])),
+                        ("phandle", cells(&[2])),
+                    ],
+                    vec![],
+                ),
+            ],
+        );
+        let dt = tree(root);
+        let graph = dt.clock_graph();
+
+        let cru = dt.find("/cru").unwrap();
+        let assigned = cru.assigned_clocks(&dt).unwrap();
+        assert_eq!(assigned.len(), 2);
+        assert_eq!(assigned[0].clock.args, vec![1]);
+        assert_eq!(assigned[0].parent, None);
+        assert_eq!(assigned[0].rate, Some(1_200_000_000));
+        assert_eq!(assigned[1].parent.as_ref().unwrap().args, vec![1]);
+        assert_eq!(assigned[1].rate, None);
+
+        let provider = graph.provider(cru).unwrap();
+        assert_eq!(provider.output_name(&[7]), Some("uart"));
+        assert_eq!(provider.output_name(&[2]), None);
+        assert_eq!(provider.parents[0].clock.node.name, "osc");
+        assert_eq!(graph.rate(&assigned[0].clock), None);
+        assert_eq!(graph.consumers.len(), 1);
+    }
+
+    #[test]
+    fn malformed_provider() {
+        let fixed = |name: &str, props: Vec<(&str, Vec<u8>)>| {
+            let mut all = vec![
+                ("compatible", b"fixed-clock\0".to_vec()),
+                ("#clock-cells", cells(&[0])),
+            ];
+            all.extend(props);
+            node(name, all, vec![])
+        };
+        let dt = tree(node(
+            "",
+            vec![],
+            vec![
+                fixed("broken", vec![]),
+                fixed("osc", vec![("clock-frequency", cells(&[19_200_000]))]),
+            ],
+        ));
+
+        let graph = dt.clock_graph();
+        assert_eq!(graph.providers.len(), 1);
+        assert_eq!(graph.providers[0].node.name, "osc");
+        assert_eq!(graph.invalid.len(), 1);
+        assert_eq!(graph.invalid[0].0.name, "broken");
+    }
+}
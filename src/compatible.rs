@@ -0,0 +1,132 @@
+//! Matching nodes by `compatible`
+//!
+//! The `compatible` property lists the programming models a device supports,
+//! from most to least specific, e.g. `"arm,pl011", "arm,primecell"`. Drivers
+//! are bound by finding the earliest entry in that list that a driver claims.
+
+use walk::PreOrder;
+use {DeviceTree, Node};
+
+/// A table of `(compatible, data)` entries, used to bind drivers to nodes.
+///
+/// ```ignore
+/// let table = MatchTable::new(&[("arm,pl011", Driver::Pl011),
+///                               ("ns16550", Driver::Ns16550)]);
+/// for (node, driver) in table.bind(&dt) {
+///     driver.probe(node);
+/// }
+/// ```
+pub struct MatchTable<'t, T: 't> {
+    entries: &'t [(&'t str, T)],
+}
+
+/// Iterator over all nodes compatible with a given string, see
+/// `DeviceTree::find_compatible`.
+pub struct CompatibleIter<'a, 'c> {
+    nodes: PreOrder<'a>,
+    compatible: &'c str,
+}
+
+impl Node {
+    /// The entries of the `compatible` property. Empty if it is missing.
+    pub fn compatible(&self) -> Vec<&str> {
+        self.prop_str_list("compatible").unwrap_or_default()
+    }
+
+    /// Whether `compatible` is listed in the node's `compatible` property.
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        self.compatible().contains(&compatible)
+    }
+
+    /// Find the best of the given `compatibles` for this node.
+    ///
+    /// Returns the index into `compatibles` of the entry that matches the
+    /// earliest, and therefore most specific, string in the node's
+    /// `compatible` property.
+    pub fn matches_compatible(&self, compatibles: &[&str]) -> Option<usize> {
+        best_match(self, |c| compatibles.iter().position(|&e| e == c))
+    }
+}
+
+impl DeviceTree {
+    /// Iterate over all nodes that are compatible with `compatible`, in
+    /// depth-first order.
+    pub fn find_compatible<'a, 'c>(&'a self, compatible: &'c str) -> CompatibleIter<'a, 'c> {
+        CompatibleIter {
+            nodes: self.nodes(),
+            compatible,
+        }
+    }
+}
+
+impl<'a, 'c> Iterator for CompatibleIter<'a, 'c> {
+    type Item = &'a Node;
+
+    fn next(&mut self) -> Option<&'a Node> {
+        let compatible = self.compatible;
+        self.nodes
+            .by_ref()
+            .map(|(_, node)| node)
+            .find(|node| node.is_compatible(compatible))
+    }
+}
+
+impl<'t, T> MatchTable<'t, T> {
+    pub fn new(entries: &'t [(&'t str, T)]) -> MatchTable<'t, T> {
+        MatchTable { entries }
+    }
+
+    /// Find the table entry that best matches `node`.
+    pub fn match_node(&self, node: &Node) -> Option<&'t T> {
+        best_match(node, |c| self.entries.iter().position(|e| e.0 == c))
+            .map(|idx| &self.entries[idx].1)
+    }
+
+    /// Match every node of `tree` against the table in a single pass,
+    /// returning the nodes that matched along with their entry.
+    pub fn bind<'a>(&self, tree: &'a DeviceTree) -> Vec<(&'a Node, &'t T)> {
+        tree.nodes()
+            .filter_map(|(_, node)| self.match_node(node).map(|data| (node, data)))
+            .collect()
+    }
+}
+
+/// Walk the node's `compatible` list from most to least specific and return
+/// the first index `lookup` finds.
+fn best_match<F>(node: &Node, lookup: F) -> Option<usize>
+where
+    F: Fn(&str) -> Option<usize>,
+{
+    node.compatible().into_iter().filter_map(lookup).next()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testutil::rpi;
+
+    #[test]
+    fn find_and_match() {
+        let dt = rpi();
+
+        assert_eq!(dt.find_compatible("fixed-clock").count(), 6);
+        assert_eq!(
+            dt.find_compatible("arm,primecell").next().unwrap().name,
+            "uart@7e201000"
+        );
+
+        let uart = dt.find("/soc/uart@7e201000").unwrap();
+        assert_eq!(
+            uart.matches_compatible(&["arm,primecell", "arm,pl011"]),
+            Some(1)
+        );
+        assert_eq!(uart.matches_compatible(&["ns16550"]), None);
+
+        let table = MatchTable::new(&[("ns16550", 1), ("arm,primecell", 2), ("arm,pl011", 3)]);
+        let bound = table.bind(&dt);
+        assert_eq!(bound.len(), 2);
+        assert_eq!(bound[0].0.name, "uart@7e201000");
+        assert_eq!(*bound[0].1, 3);
+        assert_eq!(*bound[1].1, 1);
+    }
+}
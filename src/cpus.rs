@@ -0,0 +1,309 @@
+//! CPU topology
+//!
+//! Every processor is described by a `/cpus/cpu@N` node. Its `reg` holds the
+//! hardware ID (the MPIDR affinity on ARM, the hart ID on RISC-V), encoded
+//! with the `#address-cells` of `/cpus`, and `enable-method` tells how
+//! secondary cores are started. The optional `/cpus/cpu-map` node groups
+//! CPUs into sockets, clusters, cores and threads.
+
+use {DeviceTree, Node, PropError};
+
+/// An error encountered while decoding the CPU nodes.
+#[derive(Debug)]
+pub enum CpuError {
+    /// A property could not be read.
+    PropError(PropError),
+
+    /// A `cpu` phandle in `cpu-map` does not refer to any node.
+    InvalidPhandle(u32),
+}
+
+/// How a secondary CPU is brought online.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EnableMethod<'a> {
+    /// Through PSCI `CPU_ON` calls, see the `/psci` node.
+    Psci,
+
+    /// By writing the entry point to `cpu-release-addr`.
+    SpinTable {
+        /// The address the CPU polls for its entry point.
+        release_addr: Option<u64>,
+    },
+
+    /// Any other, platform-specific method.
+    Other(&'a str),
+}
+
+/// A single CPU node.
+#[derive(Debug, PartialEq)]
+pub struct Cpu<'a> {
+    /// The `cpu@N` node.
+    pub node: &'a Node,
+
+    /// Hardware IDs from `reg`, one per hardware thread.
+    pub ids: Vec<u64>,
+
+    pub enable_method: Option<EnableMethod<'a>>,
+
+    /// Clock frequency in Hz.
+    pub clock_frequency: Option<u64>,
+
+    /// The RISC-V ISA string (`riscv,isa`).
+    pub isa: Option<&'a str>,
+}
+
+/// The position of a CPU in `cpu-map`.
+#[derive(Debug, PartialEq)]
+pub struct CpuPosition<'a> {
+    /// Index of the `socketN` node, if any.
+    pub socket: Option<u32>,
+
+    /// Indices of the (possibly nested) `clusterN` nodes, outermost first.
+    pub clusters: Vec<u32>,
+
+    /// Index of the `coreN` node.
+    pub core: u32,
+
+    /// Index of the `threadN` node, if the core has multiple threads.
+    pub thread: Option<u32>,
+
+    /// The CPU node referenced by the `cpu` phandle.
+    pub cpu: &'a Node,
+}
+
+impl<'a> Cpu<'a> {
+    /// The hardware ID of the first thread.
+    pub fn id(&self) -> Option<u64> {
+        self.ids.first().cloned()
+    }
+}
+
+impl From<PropError> for CpuError {
+    fn from(e: PropError) -> CpuError {
+        CpuError::PropError(e)
+    }
+}
+
+impl DeviceTree {
+    /// All CPU nodes below `/cpus`, in tree order.
+    pub fn cpus(&self) -> Result<Vec<Cpu<'_>>, CpuError> {
+        let parent = match self.root.child("cpus") {
+            Some(parent) => parent,
+            None => return Ok(Vec::new()),
+        };
+        let address_cells = parent.address_cells();
+
+        let mut cpus = Vec::new();
+        for node in parent.children.iter() {
+            if node.prop_str("device_type").ok() != Some("cpu") {
+                continue;
+            }
+
+            let ids = node
+                .prop_reg("reg", address_cells, 0)?
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect();
+
+            let enable_method = match node.prop_str("enable-method") {
+                Ok("psci") => Some(EnableMethod::Psci),
+                Ok("spin-table") => Some(EnableMethod::SpinTable {
+                    release_addr: node.prop_uint("cpu-release-addr").ok(),
+                }),
+                Ok(other) => Some(EnableMethod::Other(other)),
+                Err(_) => None,
+            };
+
+            cpus.push(Cpu {
+                node,
+                ids,
+                enable_method,
+                clock_frequency: node.prop_uint("clock-frequency").ok(),
+                isa: node.prop_str("riscv,isa").ok(),
+            });
+        }
+        Ok(cpus)
+    }
+
+    /// The CPU whose hardware ID matches `boot_cpuid_phys`.
+    pub fn boot_cpu(&self) -> Result<Option<Cpu<'_>>, CpuError> {
+        let boot_id = u64::from(self.boot_cpuid_phys);
+        Ok(self
+            .cpus()?
+            .into_iter()
+            .find(|cpu| cpu.ids.contains(&boot_id)))
+    }
+
+    /// Decode `/cpus/cpu-map` into the position of each CPU, in tree order.
+    ///
+    /// Returns an empty list if there is no `cpu-map`.
+    pub fn cpu_map(&self) -> Result<Vec<CpuPosition<'_>>, CpuError> {
+        let mut positions = Vec::new();
+
+        if let Some(map) = self.root.find("cpus/cpu-map") {
+            let position = Position {
+                socket: None,
+                clusters: Vec::new(),
+                core: None,
+            };
+            self.walk_cpu_map(map, &position, &mut positions)?;
+        }
+        Ok(positions)
+    }
+
+    fn walk_cpu_map<'a>(
+        &'a self,
+        node: &'a Node,
+        position: &Position,
+        positions: &mut Vec<CpuPosition<'a>>,
+    ) -> Result<(), CpuError> {
+        for child in node.children.iter() {
+            let (kind, index) = match split_index(&child.name) {
+                Some(split) => split,
+                None => continue,
+            };
+
+            let mut inner = position.clone();
+            match kind {
+                "socket" => inner.socket = Some(index),
+                "cluster" => inner.clusters.push(index),
+                "core" => inner.core = Some(index),
+                "thread" => (),
+                _ => continue,
+            }
+
+            if child.has_prop("cpu") {
+                let cpu = child.prop_u32("cpu")?;
+                positions.push(CpuPosition {
+                    socket: inner.socket,
+                    clusters: inner.clusters.clone(),
+                    core: inner.core.unwrap_or(0),
+                    thread: if kind == "thread" { Some(index) } else { None },
+                    cpu: self
+                        .find_phandle(cpu)
+                        .ok_or(CpuError::InvalidPhandle(cpu))?,
+                });
+            } else {
+                self.walk_cpu_map(child, &inner, positions)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct Position {
+    socket: Option<u32>,
+    clusters: Vec<u32>,
+    core: Option<u32>,
+}
+
+/// Split a `cpu-map` node name such as `cluster1` into `("cluster", 1)`.
+fn split_index(name: &str) -> Option<(&str, u32)> {
+    let digits = name.len() - name.bytes().rev().take_while(u8::is_ascii_digit).count();
+    let index = name[digits..].parse().ok()?;
+    Some((&name[..digits], index))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testutil::{cells, node, rpi, tree};
+
+    fn cpu(name: &str, phandle: u32, reg: &[u32], method: &str) -> Node {
+        let mut method_raw = method.as_bytes().to_vec();
+        method_raw.push(0);
+        node(
+            name,
+            vec![
+                ("device_type", b"cpu\0".to_vec()),
+                ("reg", cells(reg)),
+                ("phandle", cells(&[phandle])),
+                ("enable-method", method_raw),
+                ("cpu-release-addr", cells(&[0, 0x8000_00d8])),
+            ],
+            vec![],
+        )
+    }
+
+    #[test]
+    fn rpi_cpus() {
+        let dt = rpi();
+
+        let cpus = dt.cpus().unwrap();
+        assert_eq!(cpus.len(), 4);
+        assert_eq!(cpus[2].id(), Some(0xf02));
+        assert_eq!(cpus[2].clock_frequency, Some(800_000_000));
+        assert_eq!(cpus[2].enable_method, None);
+        assert!(dt.boot_cpu().unwrap().is_none());
+        assert!(dt.cpu_map().unwrap().is_empty());
+    }
+
+    #[test]
+    fn topology() {
+        let root = node(
+            "",
+            vec![],
+            vec![node(
+                "cpus",
+                vec![
+                    ("#address-cells", cells(&[2])),
+                    ("#size-cells", cells(&[0])),
+                ],
+                vec![
+                    node(
+                        "cpu-map",
+                        vec![],
+                        vec![
+                            node(
+                                "cluster0",
+                                vec![],
+                                vec![
+                                    node("core0", vec![("cpu", cells(&[1]))], vec![]),
+                                    node("core1", vec![("cpu", cells(&[2]))], vec![]),
+                                ],
+                            ),
+                            node(
+                                "cluster1",
+                                vec![],
+                                vec![node(
+                                    "core0",
+                                    vec![],
+                                    vec![
+                                        node("thread0", vec![("cpu", cells(&[3]))], vec![]),
+                                        node("thread1", vec![("cpu", cells(&[3]))], vec![]),
+                                    ],
+                                )],
+                            ),
+                        ],
+                    ),
+                    cpu("cpu@0", 1, &[0, 0], "psci"),
+                    cpu("cpu@1", 2, &[0, 1], "spin-table"),
+                    cpu("cpu@100", 3, &[0, 0x100, 0, 0x101], "psci"),
+                ],
+            )],
+        );
+        let mut dt = tree(root);
+        dt.boot_cpuid_phys = 0x101;
+
+        let cpus = dt.cpus().unwrap();
+        assert_eq!(cpus[0].enable_method, Some(EnableMethod::Psci));
+        assert_eq!(
+            cpus[1].enable_method,
+            Some(EnableMethod::SpinTable {
+                release_addr: Some(0x8000_00d8),
+            })
+        );
+        assert_eq!(cpus[2].ids, vec![0x100, 0x101]);
+        assert_eq!(dt.boot_cpu().unwrap().unwrap().node.name, "cpu@100");
+
+        let map = dt.cpu_map().unwrap();
+        assert_eq!(map.len(), 4);
+        assert_eq!(map[1].clusters, vec![0]);
+        assert_eq!(map[1].core, 1);
+        assert_eq!(map[1].cpu.name, "cpu@1");
+        assert_eq!(map[3].clusters, vec![1]);
+        assert_eq!(map[3].thread, Some(1));
+        assert_eq!(map[3].cpu.name, "cpu@100");
+    }
+}
@@ -0,0 +1,688 @@
+//! In-place editing of flattened device trees
+//!
+//! `FdtMut` modifies a blob directly in its buffer, like libfdt's read-write
+//! functions, instead of parsing it into a `DeviceTree` and serializing it
+//! again. Changes grow or shrink the structure and strings blocks, moving the
+//! data behind them; they must fit within the blob's `totalsize`, which can
+//! be raised up to the size of the buffer with `resize()` and shrunk to the
+//! minimum with `pack()`.
+//!
+//! Nodes are identified by their offset within the structure block, `0`
+//! being the root node. Like in libfdt, any modification may move nodes, so
+//! offsets obtained before a change must be looked up again afterwards.
+
+use util::{align, SliceRead};
+use {
+    MAGIC_NUMBER, OF_DT_BEGIN_NODE, OF_DT_END, OF_DT_END_NODE, OF_DT_NOP, OF_DT_PROP,
+    SUPPORTED_VERSION,
+};
+
+const HEADER_SIZE: usize = 40;
+const TOTALSIZE: usize = 4;
+const OFF_DT_STRUCT: usize = 8;
+const OFF_DT_STRINGS: usize = 12;
+const OFF_MEM_RSVMAP: usize = 16;
+const VERSION: usize = 20;
+const SIZE_DT_STRINGS: usize = 32;
+const SIZE_DT_STRUCT: usize = 36;
+
+/// An error encountered while reading or editing a blob in place.
+#[derive(Debug, PartialEq)]
+pub enum FdtError {
+    /// The magic number `MAGIC_NUMBER` was not found.
+    BadMagic,
+
+    /// Only version 17 blobs can be edited.
+    BadVersion,
+
+    /// The blocks overlap, are out of order or exceed the buffer.
+    BadLayout,
+
+    /// An unknown tag or malformed data was found in the structure block.
+    BadStructure,
+
+    /// The offset does not point at the start of a node.
+    BadOffset,
+
+    /// The path is not absolute.
+    BadPath,
+
+    /// The node name is empty or contains `/` or a NUL byte.
+    BadName,
+
+    /// A block ends in the middle of an entry.
+    Truncated,
+
+    /// The node or property does not exist.
+    NotFound,
+
+    /// A node with the same name already exists.
+    Exists,
+
+    /// There is not enough free space within `totalsize` for the change.
+    NoSpace,
+}
+
+/// A device tree blob that is edited in place.
+pub struct FdtMut<'a> {
+    buf: &'a mut [u8],
+}
+
+impl<'a> FdtMut<'a> {
+    /// Check the header of the blob at the start of `buf`.
+    ///
+    /// The memory reservation block, structure block and strings block must
+    /// appear in that order, as `dtc` writes them, and the structure block
+    /// must be aligned to four bytes.
+    pub fn new(buf: &'a mut [u8]) -> Result<FdtMut<'a>, FdtError> {
+        let fdt = FdtMut { buf };
+
+        if fdt.read_u32(0)? != MAGIC_NUMBER {
+            return Err(FdtError::BadMagic);
+        }
+        if fdt.read_u32(VERSION)? != SUPPORTED_VERSION {
+            return Err(FdtError::BadVersion);
+        }
+        fdt.read_u32(SIZE_DT_STRUCT)?;
+
+        let rsvmap = fdt.header(OFF_MEM_RSVMAP);
+        let struct_end = fdt.struct_start().checked_add(fdt.struct_size());
+        let data_end = fdt.strings_start().checked_add(fdt.strings_size());
+        let layout_ok = match (struct_end, data_end) {
+            (Some(struct_end), Some(data_end)) => {
+                HEADER_SIZE <= rsvmap
+                    && rsvmap <= fdt.struct_start()
+                    && fdt.struct_start() % 4 == 0
+                    && struct_end <= fdt.strings_start()
+                    && data_end <= fdt.total_size()
+                    && fdt.total_size() <= fdt.buf.len()
+            }
+            _ => false,
+        };
+        if !layout_ok {
+            return Err(FdtError::BadLayout);
+        }
+
+        Ok(fdt)
+    }
+
+    /// The size of the blob, as given by the header.
+    pub fn total_size(&self) -> usize {
+        self.header(TOTALSIZE)
+    }
+
+    /// The number of bytes between the end of the strings block and
+    /// `total_size()`, available for edits.
+    pub fn free_space(&self) -> usize {
+        self.total_size() - self.data_end()
+    }
+
+    /// The blob, `total_size()` bytes long.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.total_size()]
+    }
+
+    /// Find the node at `path`, e.g. `/soc/uart@7e201000`.
+    ///
+    /// Components without a unit address match the first node with that
+    /// base name, like in libfdt.
+    pub fn path_offset(&self, path: &str) -> Result<usize, FdtError> {
+        if !path.starts_with('/') {
+            return Err(FdtError::BadPath);
+        }
+
+        let mut node = 0;
+        for name in path.split('/').filter(|n| !n.is_empty()) {
+            node = self.subnode_offset(node, name)?;
+        }
+        Ok(node)
+    }
+
+    /// Find the child `name` of `parent`.
+    pub fn subnode_offset(&self, parent: usize, name: &str) -> Result<usize, FdtError> {
+        let mut child = self.first_child(parent)?;
+
+        while let Some(node) = child {
+            let node_name = self.node_name(node)?;
+            let base = node_name.split('@').next().unwrap_or("");
+            if node_name == name || (!name.contains('@') && base == name) {
+                return Ok(node);
+            }
+            child = self.next_sibling(node)?;
+        }
+        Err(FdtError::NotFound)
+    }
+
+    /// The name of the node at `node`, including its unit address.
+    pub fn node_name(&self, node: usize) -> Result<&str, FdtError> {
+        self.check_node(node)?;
+        let start = self.struct_start() + node + 4;
+        let name = self.bstring0(start, self.struct_end())?;
+        ::core::str::from_utf8(name).map_err(|_| FdtError::BadStructure)
+    }
+
+    /// The first child of `node`, if any.
+    pub fn first_child(&self, node: usize) -> Result<Option<usize>, FdtError> {
+        let offset = self.props_end(node)?;
+        Ok(match self.tag(offset)?.0 {
+            OF_DT_BEGIN_NODE => Some(offset),
+            _ => None,
+        })
+    }
+
+    /// The next sibling of `node`, if any.
+    pub fn next_sibling(&self, node: usize) -> Result<Option<usize>, FdtError> {
+        let mut offset = self.node_end(node)?;
+        loop {
+            let (tag, next) = self.tag(offset)?;
+            match tag {
+                OF_DT_NOP => offset = next,
+                OF_DT_BEGIN_NODE => return Ok(Some(offset)),
+                _ => return Ok(None),
+            }
+        }
+    }
+
+    /// The value of property `name` of `node`.
+    pub fn getprop(&self, node: usize, name: &str) -> Result<&[u8], FdtError> {
+        let offset = self
+            .property_offset(node, name)?
+            .ok_or(FdtError::NotFound)?;
+        let start = self.struct_start() + offset + 12;
+        let len = self.read_u32(start - 8)? as usize;
+        Ok(&self.buf[start..(start + len)])
+    }
+
+    /// Set property `name` of `node` to `value`, adding it after the existing
+    /// properties if it does not exist yet.
+    pub fn setprop(&mut self, node: usize, name: &str, value: &[u8]) -> Result<(), FdtError> {
+        let offset = match self.property_offset(node, name)? {
+            Some(offset) => {
+                let old_len = self.read_u32(self.struct_start() + offset + 4)? as usize;
+                self.splice_struct(offset + 12, align(old_len, 4), align(value.len(), 4))?;
+                offset
+            }
+            None => {
+                let size = 12 + align(value.len(), 4);
+                let string_size = match self.find_string(name) {
+                    Some(_) => 0,
+                    None => name.len() + 1,
+                };
+                if size + string_size > self.free_space() {
+                    return Err(FdtError::NoSpace);
+                }
+
+                let name_offset = self.add_string(name)?;
+                let offset = self.props_end(node)?;
+                self.splice_struct(offset, 0, size)?;
+
+                let start = self.struct_start() + offset;
+                self.write_u32(start, OF_DT_PROP);
+                self.write_u32(start + 8, name_offset);
+                offset
+            }
+        };
+
+        let start = self.struct_start() + offset;
+        self.write_u32(start + 4, value.len() as u32);
+        self.write_padded(start + 12, value);
+        Ok(())
+    }
+
+    /// Set property `name` of `node` to a single cell.
+    pub fn setprop_u32(&mut self, node: usize, name: &str, value: u32) -> Result<(), FdtError> {
+        self.setprop(node, name, &value.to_be_bytes())
+    }
+
+    /// Set property `name` of `node` to a null-terminated string.
+    pub fn setprop_str(&mut self, node: usize, name: &str, value: &str) -> Result<(), FdtError> {
+        let mut raw = Vec::with_capacity(value.len() + 1);
+        raw.extend_from_slice(value.as_bytes());
+        raw.push(0);
+        self.setprop(node, name, &raw)
+    }
+
+    /// Append `value` to property `name` of `node`, creating the property if
+    /// it does not exist.
+    pub fn appendprop(&mut self, node: usize, name: &str, value: &[u8]) -> Result<(), FdtError> {
+        let offset = match self.property_offset(node, name)? {
+            Some(offset) => offset,
+            None => return self.setprop(node, name, value),
+        };
+
+        let old_len = self.read_u32(self.struct_start() + offset + 4)? as usize;
+        let new_len = old_len + value.len();
+        self.splice_struct(offset + 12, align(old_len, 4), align(new_len, 4))?;
+
+        let start = self.struct_start() + offset;
+        self.write_u32(start + 4, new_len as u32);
+        self.write_padded(start + 12 + old_len, value);
+        Ok(())
+    }
+
+    /// Remove property `name` from `node`.
+    pub fn delprop(&mut self, node: usize, name: &str) -> Result<(), FdtError> {
+        let offset = self
+            .property_offset(node, name)?
+            .ok_or(FdtError::NotFound)?;
+        let len = self.read_u32(self.struct_start() + offset + 4)? as usize;
+        self.splice_struct(offset, 12 + align(len, 4), 0)
+    }
+
+    /// Add an empty node `name` as the last child of `parent`, returning its
+    /// offset.
+    ///
+    /// Like in libfdt, a name without unit address also clashes with
+    /// children that have the same base name, as `subnode_offset()` would
+    /// find them.
+    pub fn add_subnode(&mut self, parent: usize, name: &str) -> Result<usize, FdtError> {
+        if name.is_empty() || name.contains('/') || name.contains('\0') {
+            return Err(FdtError::BadName);
+        }
+        match self.subnode_offset(parent, name) {
+            Ok(_) => return Err(FdtError::Exists),
+            Err(FdtError::NotFound) => (),
+            Err(e) => return Err(e),
+        }
+
+        // insert before the END_NODE of the parent
+        let offset = self.node_end(parent)? - 4;
+        let name_size = align(name.len() + 1, 4);
+        self.splice_struct(offset, 0, 8 + name_size)?;
+
+        let start = self.struct_start() + offset;
+        self.write_u32(start, OF_DT_BEGIN_NODE);
+        self.write_padded(start + 4, name.as_bytes());
+        self.write_u32(start + 4 + name_size, OF_DT_END_NODE);
+        Ok(offset)
+    }
+
+    /// Remove `node` and all its descendants.
+    pub fn del_node(&mut self, node: usize) -> Result<(), FdtError> {
+        if node == 0 {
+            return Err(FdtError::BadOffset);
+        }
+        let end = self.node_end(node)?;
+        self.splice_struct(node, end - node, 0)
+    }
+
+    /// Remove all gaps between the blocks and shrink `total_size()` to the
+    /// end of the strings block, leaving no free space.
+    pub fn pack(&mut self) -> Result<(), FdtError> {
+        let rsvmap_start = self.header(OFF_MEM_RSVMAP);
+        let mut rsvmap_end = rsvmap_start;
+        loop {
+            let slice: &[u8] = &self.buf[..self.struct_start()];
+            let size = slice
+                .read_be_u64(rsvmap_end + 8)
+                .map_err(|_| FdtError::Truncated)?;
+            rsvmap_end += 16;
+            if size == 0 {
+                break;
+            }
+        }
+
+        let mut pos = align(HEADER_SIZE, 8);
+        self.buf.copy_within(rsvmap_start..rsvmap_end, pos);
+        self.set_header(OFF_MEM_RSVMAP, pos);
+        pos += rsvmap_end - rsvmap_start;
+
+        let (struct_start, struct_size) = (self.struct_start(), self.struct_size());
+        self.buf
+            .copy_within(struct_start..(struct_start + struct_size), pos);
+        self.set_header(OFF_DT_STRUCT, pos);
+        pos += struct_size;
+
+        let (strings_start, strings_size) = (self.strings_start(), self.strings_size());
+        self.buf
+            .copy_within(strings_start..(strings_start + strings_size), pos);
+        self.set_header(OFF_DT_STRINGS, pos);
+        pos += strings_size;
+
+        self.set_header(TOTALSIZE, pos);
+        Ok(())
+    }
+
+    /// Change `total_size()`, e.g. to make room for edits after `pack()`.
+    ///
+    /// The new size must hold all blocks and fit into the buffer.
+    pub fn resize(&mut self, size: usize) -> Result<(), FdtError> {
+        if size < self.data_end() || size > self.buf.len() {
+            return Err(FdtError::NoSpace);
+        }
+        self.set_header(TOTALSIZE, size);
+        Ok(())
+    }
+
+    fn header(&self, field: usize) -> usize {
+        (&self.buf[..]).read_be_u32(field).unwrap_or(0) as usize
+    }
+
+    fn set_header(&mut self, field: usize, val: usize) {
+        self.write_u32(field, val as u32);
+    }
+
+    fn struct_start(&self) -> usize {
+        self.header(OFF_DT_STRUCT)
+    }
+
+    fn struct_size(&self) -> usize {
+        self.header(SIZE_DT_STRUCT)
+    }
+
+    fn struct_end(&self) -> usize {
+        self.struct_start() + self.struct_size()
+    }
+
+    fn strings_start(&self) -> usize {
+        self.header(OFF_DT_STRINGS)
+    }
+
+    fn strings_size(&self) -> usize {
+        self.header(SIZE_DT_STRINGS)
+    }
+
+    fn data_end(&self) -> usize {
+        self.strings_start() + self.strings_size()
+    }
+
+    fn read_u32(&self, pos: usize) -> Result<u32, FdtError> {
+        (&self.buf[..])
+            .read_be_u32(pos)
+            .map_err(|_| FdtError::Truncated)
+    }
+
+    fn write_u32(&mut self, pos: usize, val: u32) {
+        self.buf[pos..(pos + 4)].copy_from_slice(&val.to_be_bytes());
+    }
+
+    /// Write `data` at `pos`, zeroing the bytes up to the next multiple of
+    /// four.
+    fn write_padded(&mut self, pos: usize, data: &[u8]) {
+        let end = pos + data.len();
+        self.buf[pos..end].copy_from_slice(data);
+        for b in self.buf[end..align(end, 4)].iter_mut() {
+            *b = 0;
+        }
+    }
+
+    /// Decode the tag at structure offset `offset`, returning it along with
+    /// the offset of the next tag.
+    fn tag(&self, offset: usize) -> Result<(u32, usize), FdtError> {
+        let base = self.struct_start();
+        if offset + 4 > self.struct_size() {
+            return Err(FdtError::Truncated);
+        }
+
+        let tag = self.read_u32(base + offset)?;
+        let next = match tag {
+            OF_DT_BEGIN_NODE => {
+                let name = self.bstring0(base + offset + 4, self.struct_end())?;
+                align(offset + 4 + name.len() + 1, 4)
+            }
+            OF_DT_PROP => {
+                let len = self.read_u32(base + offset + 4)? as usize;
+                let end = (offset + 12).checked_add(len).ok_or(FdtError::Truncated)?;
+                if end > self.struct_size() {
+                    return Err(FdtError::Truncated);
+                }
+                align(end, 4)
+            }
+            OF_DT_END_NODE | OF_DT_NOP | OF_DT_END => offset + 4,
+            _ => return Err(FdtError::BadStructure),
+        };
+
+        if next > self.struct_size() {
+            return Err(FdtError::Truncated);
+        }
+        Ok((tag, next))
+    }
+
+    fn check_node(&self, node: usize) -> Result<usize, FdtError> {
+        if node % 4 != 0 {
+            return Err(FdtError::BadOffset);
+        }
+        match self.tag(node)? {
+            (OF_DT_BEGIN_NODE, next) => Ok(next),
+            _ => Err(FdtError::BadOffset),
+        }
+    }
+
+    /// The offset of the first tag after the properties of `node`.
+    fn props_end(&self, node: usize) -> Result<usize, FdtError> {
+        let mut offset = self.check_node(node)?;
+        loop {
+            let (tag, next) = self.tag(offset)?;
+            match tag {
+                OF_DT_PROP | OF_DT_NOP => offset = next,
+                _ => return Ok(offset),
+            }
+        }
+    }
+
+    /// The offset right after the `END_NODE` tag of `node`.
+    fn node_end(&self, node: usize) -> Result<usize, FdtError> {
+        let mut offset = self.check_node(node)?;
+        let mut depth = 1;
+        loop {
+            let (tag, next) = self.tag(offset)?;
+            match tag {
+                OF_DT_BEGIN_NODE => depth += 1,
+                OF_DT_END_NODE => depth -= 1,
+                OF_DT_END => return Err(FdtError::BadStructure),
+                _ => (),
+            }
+            offset = next;
+            if depth == 0 {
+                return Ok(offset);
+            }
+        }
+    }
+
+    fn property_offset(&self, node: usize, name: &str) -> Result<Option<usize>, FdtError> {
+        let mut offset = self.check_node(node)?;
+        loop {
+            let (tag, next) = self.tag(offset)?;
+            match tag {
+                OF_DT_NOP => (),
+                OF_DT_PROP => {
+                    let name_offset = self.read_u32(self.struct_start() + offset + 8)? as usize;
+                    if self.string_at(name_offset)? == name.as_bytes() {
+                        return Ok(Some(offset));
+                    }
+                }
+                _ => return Ok(None),
+            }
+            offset = next;
+        }
+    }
+
+    fn string_at(&self, offset: usize) -> Result<&[u8], FdtError> {
+        if offset >= self.strings_size() {
+            return Err(FdtError::Truncated);
+        }
+        self.bstring0(self.strings_start() + offset, self.data_end())
+    }
+
+    /// The null-terminated string at `pos`, which must end before `end`.
+    fn bstring0(&self, pos: usize, end: usize) -> Result<&[u8], FdtError> {
+        let bytes = &self.buf[pos.min(end)..end];
+        match bytes.iter().position(|&b| b == 0) {
+            Some(len) => Ok(&bytes[..len]),
+            None => Err(FdtError::Truncated),
+        }
+    }
+
+    /// Find `name` in the strings block, possibly as the suffix of a longer
+    /// string.
+    fn find_string(&self, name: &str) -> Option<u32> {
+        let strings = &self.buf[self.strings_start()..self.data_end()];
+        let len = name.len() + 1;
+
+        (0..(strings.len() + 1).saturating_sub(len))
+            .find(|&i| &strings[i..(i + len - 1)] == name.as_bytes() && strings[i + len - 1] == 0)
+            .map(|i| i as u32)
+    }
+
+    fn add_string(&mut self, name: &str) -> Result<u32, FdtError> {
+        if let Some(offset) = self.find_string(name) {
+            return Ok(offset);
+        }
+
+        let offset = self.strings_size();
+        let end = self.data_end();
+        self.splice(end, 0, name.len() + 1)?;
+        self.buf[end..(end + name.len())].copy_from_slice(name.as_bytes());
+        self.buf[end + name.len()] = 0;
+        self.set_header(SIZE_DT_STRINGS, offset + name.len() + 1);
+        Ok(offset as u32)
+    }
+
+    /// Replace `old_len` bytes at `pos` with `new_len` bytes, moving the
+    /// data behind them. New bytes are zeroed.
+    fn splice(&mut self, pos: usize, old_len: usize, new_len: usize) -> Result<(), FdtError> {
+        let end = self.data_end();
+        if end - old_len + new_len > self.total_size() {
+            return Err(FdtError::NoSpace);
+        }
+
+        self.buf.copy_within((pos + old_len)..end, pos + new_len);
+        if new_len > old_len {
+            for b in self.buf[(pos + old_len)..(pos + new_len)].iter_mut() {
+                *b = 0;
+            }
+        }
+        Ok(())
+    }
+
+    fn splice_struct(
+        &mut self,
+        offset: usize,
+        old_len: usize,
+        new_len: usize,
+    ) -> Result<(), FdtError> {
+        let pos = self.struct_start() + offset;
+        self.splice(pos, old_len, new_len)?;
+
+        let struct_size = self.struct_size() + new_len - old_len;
+        let strings_start = self.strings_start() + new_len - old_len;
+        self.set_header(SIZE_DT_STRUCT, struct_size);
+        self.set_header(OFF_DT_STRINGS, strings_start);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use store::StoreOptions;
+    use testutil::rpi;
+    use {DeviceTree, Node};
+
+    #[test]
+    fn edit_in_place() {
+        let mut dt = rpi();
+        let mut blob = dt
+            .store_with(&StoreOptions {
+                padding: 256,
+                ..StoreOptions::default()
+            })
+            .unwrap();
+        let mut fdt = FdtMut::new(&mut blob).unwrap();
+
+        let chosen = fdt.path_offset("/chosen").unwrap();
+        fdt.setprop_str(chosen, "bootargs", "console=ttyAMA0")
+            .unwrap();
+        fdt.setprop_u32(chosen, "linux,initrd-start", 0x0800_0000)
+            .unwrap();
+        fdt.appendprop(chosen, "linux,initrd-start", &[0, 0, 0, 1])
+            .unwrap();
+        assert_eq!(
+            fdt.getprop(chosen, "linux,initrd-start").unwrap(),
+            &[8, 0, 0, 0, 0, 0, 0, 1]
+        );
+
+        let uart = fdt.path_offset("/soc/uart@7e201000").unwrap();
+        fdt.delprop(uart, "clock-names").unwrap();
+        assert_eq!(fdt.delprop(uart, "clock-names"), Err(FdtError::NotFound));
+
+        let root = fdt.path_offset("/").unwrap();
+        let node = fdt.add_subnode(root, "fixup").unwrap();
+        fdt.setprop(node, "status", b"okay\0").unwrap();
+        assert_eq!(fdt.add_subnode(root, "fixup"), Err(FdtError::Exists));
+        let soc = fdt.path_offset("/soc").unwrap();
+        assert_eq!(fdt.add_subnode(soc, "gpio"), Err(FdtError::Exists));
+        for name in ["", "a/b", "a\0b"].iter() {
+            assert_eq!(fdt.add_subnode(root, name), Err(FdtError::BadName));
+        }
+
+        let audio = fdt.path_offset("/audio").unwrap();
+        fdt.del_node(audio).unwrap();
+        assert_eq!(fdt.path_offset("/audio"), Err(FdtError::NotFound));
+        assert_eq!(
+            fdt.path_offset("/soc/gpio"),
+            fdt.path_offset("/soc/gpio@7e200000")
+        );
+
+        {
+            let chosen = dt.find_mut("/chosen").unwrap();
+            chosen.set_prop_str("bootargs", "console=ttyAMA0");
+            chosen.set_prop_cells("linux,initrd-start", &[0x0800_0000, 1]);
+        }
+        dt.find_mut("/soc/uart@7e201000")
+            .unwrap()
+            .remove_prop("clock-names");
+        let mut fixup = Node {
+            name: "fixup".to_owned(),
+            props: Vec::new(),
+            children: Vec::new(),
+        };
+        fixup.set_prop_str("status", "okay");
+        dt.root.children.push(fixup);
+        dt.root.children.retain(|n| n.name != "audio");
+
+        assert_eq!(DeviceTree::load(fdt.as_bytes()).unwrap(), dt);
+
+        let free = fdt.free_space();
+        fdt.pack().unwrap();
+        assert_eq!(fdt.free_space(), 0);
+        assert_eq!(DeviceTree::load(fdt.as_bytes()).unwrap(), dt);
+        assert_eq!(fdt.setprop(root, "big", &[1; 64]), Err(FdtError::NoSpace));
+        let chosen = fdt.path_offset("/chosen").unwrap();
+        assert_eq!(
+            fdt.setprop_str(chosen, "bootargs", "console=ttyAMA0,115200"),
+            Err(FdtError::NoSpace)
+        );
+        assert_eq!(
+            fdt.getprop(chosen, "bootargs").unwrap(),
+            b"console=ttyAMA0\0"
+        );
+
+        let size = fdt.total_size();
+        fdt.resize(size + free).unwrap();
+        fdt.setprop(root, "big", &[1; 64]).unwrap();
+        assert_eq!(fdt.resize(size), Err(FdtError::NoSpace));
+    }
+
+    #[test]
+    fn reject_bad_blobs() {
+        let mut blob = rpi().store().unwrap();
+        blob[20] = 0;
+        blob[23] = 16;
+        assert!(FdtMut::new(&mut blob).is_err());
+
+        let mut blob = rpi().store().unwrap();
+        blob[0] = 0;
+        assert_eq!(FdtMut::new(&mut blob).err(), Some(FdtError::BadMagic));
+
+        // unaligned structure block
+        let mut blob = rpi().store().unwrap();
+        blob[11] -= 1;
+        assert_eq!(FdtMut::new(&mut blob).err(), Some(FdtError::BadLayout));
+
+        // sizes beyond the buffer, whose sums overflow on 32 bit targets
+        let mut blob = rpi().store().unwrap();
+        blob[32..36].copy_from_slice(&[0xff; 4]);
+        assert_eq!(FdtMut::new(&mut blob).err(), Some(FdtError::BadLayout));
+    }
+}
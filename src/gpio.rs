@@ -0,0 +1,229 @@
+//! GPIO consumers
+//!
+//! Devices reference GPIO lines through `<function>-gpios` properties (plain
+//! `gpios` for unnamed lines, and the deprecated `<function>-gpio`). Each
+//! entry is a phandle of a `gpio-controller`, a pin specifier and, with the
+//! common two-cell binding, a flags cell using the values from
+//! `include/dt-bindings/gpio/gpio.h`. Entries are remapped through
+//! `gpio-map` nexus nodes.
+
+use phandle::PhandleError;
+use {DeviceTree, Node, PropError};
+
+pub const GPIO_ACTIVE_LOW: u32 = 0x1;
+pub const GPIO_SINGLE_ENDED: u32 = 0x2;
+pub const GPIO_LINE_OPEN_DRAIN: u32 = 0x4;
+pub const GPIO_TRANSITORY: u32 = 0x8;
+pub const GPIO_PULL_UP: u32 = 0x10;
+pub const GPIO_PULL_DOWN: u32 = 0x20;
+
+/// The flags cell of a GPIO specifier.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GpioFlags(pub u32);
+
+/// A single GPIO line.
+#[derive(Debug, PartialEq)]
+pub struct Gpio<'a> {
+    /// The `gpio-controller` the line belongs to.
+    pub controller: &'a Node,
+
+    /// The specifier, in the controller's format.
+    pub specifier: Vec<u32>,
+
+    /// The flags, taken from the last cell of two- or more-cell
+    /// specifiers. Empty for single-cell specifiers.
+    pub flags: GpioFlags,
+}
+
+impl GpioFlags {
+    pub fn is_active_low(&self) -> bool {
+        self.0 & GPIO_ACTIVE_LOW != 0
+    }
+
+    /// The line is only driven low and floats otherwise.
+    pub fn is_open_drain(&self) -> bool {
+        self.0 & (GPIO_SINGLE_ENDED | GPIO_LINE_OPEN_DRAIN)
+            == GPIO_SINGLE_ENDED | GPIO_LINE_OPEN_DRAIN
+    }
+
+    /// The line is only driven high and floats otherwise.
+    pub fn is_open_source(&self) -> bool {
+        self.0 & (GPIO_SINGLE_ENDED | GPIO_LINE_OPEN_DRAIN) == GPIO_SINGLE_ENDED
+    }
+
+    /// The line state may be lost during suspend.
+    pub fn is_transitory(&self) -> bool {
+        self.0 & GPIO_TRANSITORY != 0
+    }
+
+    pub fn is_pull_up(&self) -> bool {
+        self.0 & GPIO_PULL_UP != 0
+    }
+
+    pub fn is_pull_down(&self) -> bool {
+        self.0 & GPIO_PULL_DOWN != 0
+    }
+}
+
+impl<'a> Gpio<'a> {
+    /// The pin number, i.e. the first specifier cell.
+    pub fn pin(&self) -> Option<u32> {
+        self.specifier.first().cloned()
+    }
+}
+
+impl Node {
+    /// The GPIO lines of function `function`, e.g. `"reset"` for
+    /// `reset-gpios`, or `""` for `gpios`.
+    ///
+    /// Empty entries (a phandle of `0`) are kept as `None`, since bindings
+    /// like `cs-gpios` give them a meaning. Returns an empty list if the
+    /// property does not exist.
+    pub fn gpios<'a>(
+        &self,
+        tree: &'a DeviceTree,
+        function: &str,
+    ) -> Result<Vec<Option<Gpio<'a>>>, PhandleError> {
+        let name = match self.gpio_prop_name(function) {
+            Some(name) => name,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut gpios = Vec::new();
+        for entry in self.phandle_args(tree, &name, "#gpio-cells")? {
+            let entry = match entry {
+                Ok(entry) => entry.map(tree, "gpio")?,
+                Err(PhandleError::Empty) => {
+                    gpios.push(None);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            let flags = match entry.args.len() {
+                0 | 1 => 0,
+                n => entry.args[n - 1],
+            };
+            gpios.push(Some(Gpio {
+                controller: entry.node,
+                specifier: entry.args,
+                flags: GpioFlags(flags),
+            }));
+        }
+        Ok(gpios)
+    }
+
+    /// The GPIO line at `index` of function `function`.
+    pub fn gpio<'a>(
+        &self,
+        tree: &'a DeviceTree,
+        function: &str,
+        index: usize,
+    ) -> Result<Gpio<'a>, PhandleError> {
+        match self.gpios(tree, function)?.into_iter().nth(index) {
+            Some(Some(gpio)) => Ok(gpio),
+            Some(None) => Err(PhandleError::Empty),
+            None => Err(PhandleError::NotFound),
+        }
+    }
+
+    /// Whether this node is a GPIO controller.
+    pub fn is_gpio_controller(&self) -> bool {
+        self.has_prop("gpio-controller")
+    }
+
+    /// The number of lines of a GPIO controller (`ngpios`).
+    pub fn ngpios(&self) -> Result<u32, PropError> {
+        self.prop_u32("ngpios")
+    }
+
+    fn gpio_prop_name(&self, function: &str) -> Option<String> {
+        let names = if function.is_empty() {
+            ["gpios".to_owned(), "gpio".to_owned()]
+        } else {
+            [format!("{}-gpios", function), format!("{}-gpio", function)]
+        };
+        names.iter().find(|n| self.has_prop(n)).cloned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testutil::{cells, node, rpi, tree};
+
+    #[test]
+    fn rpi_gpios() {
+        let dt = rpi();
+
+        let led = dt.find("/soc/leds/act").unwrap();
+        let gpio = led.gpio(&dt, "", 0).unwrap();
+        assert_eq!(gpio.controller.name, "gpio@7e200000");
+        assert!(gpio.controller.is_gpio_controller());
+        assert_eq!(gpio.pin(), Some(47));
+        assert!(!gpio.flags.is_active_low());
+
+        let spi = dt.find("/soc/spi@7e204000").unwrap();
+        assert_eq!(spi.gpios(&dt, "cs").unwrap(), vec![None, None]);
+        assert!(spi.gpios(&dt, "reset").unwrap().is_empty());
+    }
+
+    #[test]
+    fn flags_and_nexus() {
+        let root = node(
+            "",
+            vec![],
+            vec![
+                node(
+                    "gpio",
+                    vec![
+                        ("gpio-controller", vec![]),
+                        ("#gpio-cells", cells(&[2])),
+                        ("phandle", cells(&[1])),
+                    ],
+                    vec![],
+                ),
+                node(
+                    "connector",
+                    vec![
+                        ("#gpio-cells", cells(&[2])),
+                        ("gpio-map", cells(&[0, 0, 1, 12, 0, 1, 0, 1, 13, 0])),
+                        ("gpio-map-mask", cells(&[0xf, 0])),
+                        ("gpio-map-pass-thru", cells(&[0, 0xffff_ffff])),
+                        ("phandle", cells(&[2])),
+                    ],
+                    vec![],
+                ),
+                node(
+                    "device",
+                    vec![
+                        ("reset-gpios", cells(&[1, 5, GPIO_ACTIVE_LOW])),
+                        ("enable-gpio", cells(&[1, 6, 6])),
+                        ("irq-gpios", cells(&[2, 1, GPIO_PULL_UP])),
+                    ],
+                    vec![],
+                ),
+            ],
+        );
+        let dt = tree(root);
+        let device = dt.find("/device").unwrap();
+
+        let reset = device.gpio(&dt, "reset", 0).unwrap();
+        assert_eq!(reset.specifier, vec![5, GPIO_ACTIVE_LOW]);
+        assert!(reset.flags.is_active_low());
+
+        let enable = device.gpio(&dt, "enable", 0).unwrap();
+        assert!(enable.flags.is_open_drain());
+        assert!(!enable.flags.is_open_source());
+
+        let irq = device.gpio(&dt, "irq", 0).unwrap();
+        assert_eq!(irq.controller.name, "gpio");
+        assert_eq!(irq.pin(), Some(13));
+        assert!(irq.flags.is_pull_up());
+
+        assert!(matches!(
+            device.gpio(&dt, "reset", 1),
+            Err(PhandleError::NotFound)
+        ));
+    }
+}
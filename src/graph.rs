@@ -0,0 +1,343 @@
+//! OF graph: ports and endpoints
+//!
+//! Data pipelines, e.g. between a display controller, an encoder and a
+//! panel, are described by `port` nodes containing `endpoint` nodes. Each
+//! endpoint references its counterpart on the other device through
+//! `remote-endpoint`. Devices with several ports put them in a `ports`
+//! container and number them with `reg`, as they do with multiple endpoints
+//! in a port.
+//!
+//! See `Documentation/devicetree/bindings/graph.txt` in the kernel.
+
+use {DeviceTree, Node, PropError};
+
+/// An error encountered while following graph connections.
+#[derive(Debug)]
+pub enum GraphError {
+    /// A property could not be read.
+    PropError(PropError),
+
+    /// A `remote-endpoint` phandle does not refer to any node.
+    InvalidPhandle(u32),
+
+    /// The node is not an endpoint within a port.
+    NotEndpoint,
+}
+
+/// A `port` node of a device.
+#[derive(Debug, PartialEq)]
+pub struct Port<'a> {
+    pub node: &'a Node,
+
+    /// The port number from `reg`, `0` if there is none.
+    pub id: u32,
+}
+
+/// An `endpoint` node, along with the port and device it belongs to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Endpoint<'a> {
+    pub node: &'a Node,
+
+    /// The device owning the port.
+    pub device: &'a Node,
+
+    /// The number of the port.
+    pub port: u32,
+
+    /// The endpoint number from `reg`, `0` if there is none.
+    pub id: u32,
+}
+
+/// A connection between two endpoints, see `DeviceTree::graph`.
+#[derive(Debug, PartialEq)]
+pub struct Link<'a> {
+    pub local: Endpoint<'a>,
+    pub remote: Endpoint<'a>,
+
+    /// Whether the remote endpoint links back to the local one, as the
+    /// binding requires.
+    pub bidirectional: bool,
+}
+
+impl From<PropError> for GraphError {
+    fn from(e: PropError) -> GraphError {
+        GraphError::PropError(e)
+    }
+}
+
+impl<'a> Port<'a> {
+    /// The endpoints of this port, in tree order.
+    pub fn endpoints(&self) -> Vec<&'a Node> {
+        self.node
+            .children
+            .iter()
+            .filter(|n| n.base_name() == "endpoint")
+            .collect()
+    }
+}
+
+impl Node {
+    /// The ports of this device, either direct children or inside a `ports`
+    /// container, in tree order.
+    pub fn ports(&self) -> Vec<Port<'_>> {
+        let container = self.child("ports").unwrap_or(self);
+
+        container
+            .children
+            .iter()
+            .filter(|n| n.base_name() == "port")
+            .map(|node| Port {
+                node,
+                id: node.prop_u32("reg").unwrap_or(0),
+            })
+            .collect()
+    }
+
+    /// The port numbered `id`.
+    pub fn port(&self, id: u32) -> Option<Port<'_>> {
+        self.ports().into_iter().find(|p| p.id == id)
+    }
+}
+
+impl DeviceTree {
+    /// Decode an `endpoint` node, finding its port and device.
+    pub fn endpoint<'a>(&'a self, node: &'a Node) -> Result<Endpoint<'a>, GraphError> {
+        let port = self.parent_of(node).ok_or(GraphError::NotEndpoint)?;
+        if node.base_name() != "endpoint" || port.base_name() != "port" {
+            return Err(GraphError::NotEndpoint);
+        }
+
+        let mut device = self.parent_of(port).ok_or(GraphError::NotEndpoint)?;
+        if device.name == "ports" {
+            device = self.parent_of(device).ok_or(GraphError::NotEndpoint)?;
+        }
+
+        Ok(Endpoint {
+            node,
+            device,
+            port: port.prop_u32("reg").unwrap_or(0),
+            id: node.prop_u32("reg").unwrap_or(0),
+        })
+    }
+
+    /// All endpoints of `device`, in tree order.
+    pub fn endpoints<'a>(&'a self, device: &'a Node) -> Result<Vec<Endpoint<'a>>, GraphError> {
+        let mut endpoints = Vec::new();
+        for port in device.ports() {
+            for node in port.endpoints() {
+                endpoints.push(Endpoint {
+                    node,
+                    device,
+                    port: port.id,
+                    id: node.prop_u32("reg").unwrap_or(0),
+                });
+            }
+        }
+        Ok(endpoints)
+    }
+
+    /// Follow `remote-endpoint` of `endpoint` to the endpoint on the other
+    /// side of the link.
+    pub fn remote_endpoint<'a>(
+        &'a self,
+        endpoint: &Endpoint<'a>,
+    ) -> Result<Endpoint<'a>, GraphError> {
+        let phandle = endpoint.node.prop_u32("remote-endpoint")?;
+        let remote = self
+            .find_phandle(phandle)
+            .ok_or(GraphError::InvalidPhandle(phandle))?;
+        self.endpoint(remote)
+    }
+
+    /// The devices `device` is connected to, in the order of its endpoints.
+    pub fn remote_devices<'a>(&'a self, device: &'a Node) -> Result<Vec<&'a Node>, GraphError> {
+        let mut devices = Vec::new();
+        for endpoint in self.endpoints(device)? {
+            if !endpoint.node.has_prop("remote-endpoint") {
+                continue;
+            }
+            devices.push(self.remote_endpoint(&endpoint)?.device);
+        }
+        Ok(devices)
+    }
+
+    /// Every link in the tree, one per endpoint with a `remote-endpoint`,
+    /// in tree order of the local endpoint.
+    ///
+    /// A well-formed graph lists each connection twice, once from either
+    /// side, and all links are `bidirectional`.
+    pub fn graph(&self) -> Result<Vec<Link<'_>>, GraphError> {
+        let mut endpoints = Vec::new();
+        let mut ancestors = Vec::new();
+        for (depth, node) in self.nodes() {
+            ancestors.truncate(depth);
+            endpoints.extend(endpoint_below(&ancestors, node));
+            ancestors.push(node);
+        }
+
+        // follow `remote-endpoint` without searching the tree every time
+        let mut by_phandle: Vec<(u32, &Endpoint)> = endpoints
+            .iter()
+            .filter_map(|e| e.node.phandle().map(|phandle| (phandle, e)))
+            .collect();
+        by_phandle.sort_by_key(|&(phandle, _)| phandle);
+        by_phandle.dedup_by_key(|&mut (phandle, _)| phandle);
+        let find = |phandle| {
+            by_phandle
+                .binary_search_by_key(&phandle, |&(phandle, _)| phandle)
+                .ok()
+                .map(|i| by_phandle[i].1)
+        };
+
+        let mut links = Vec::new();
+        for local in endpoints.iter() {
+            if !local.node.has_prop("remote-endpoint") {
+                continue;
+            }
+
+            let phandle = local.node.prop_u32("remote-endpoint")?;
+            let remote = match find(phandle) {
+                Some(remote) => remote,
+                None if self.find_phandle(phandle).is_some() => {
+                    return Err(GraphError::NotEndpoint)
+                }
+                None => return Err(GraphError::InvalidPhandle(phandle)),
+            };
+            let bidirectional = remote
+                .node
+                .prop_u32("remote-endpoint")
+                .ok()
+                .and_then(&find)
+                .map_or(false, |back| ::core::ptr::eq(back.node, local.node));
+
+            links.push(Link {
+                local: local.clone(),
+                remote: remote.clone(),
+                bidirectional,
+            });
+        }
+        Ok(links)
+    }
+}
+
+/// Decode `node` as an endpoint, given its ancestors from the root down to
+/// its parent.
+fn endpoint_below<'a>(ancestors: &[&'a Node], node: &'a Node) -> Option<Endpoint<'a>> {
+    let (&port, rest) = ancestors.split_last()?;
+    if node.base_name() != "endpoint" || port.base_name() != "port" {
+        return None;
+    }
+
+    let (&parent, rest) = rest.split_last()?;
+    let device = match parent.name.as_str() {
+        "ports" => *rest.last()?,
+        _ => parent,
+    };
+
+    Some(Endpoint {
+        node,
+        device,
+        port: port.prop_u32("reg").unwrap_or(0),
+        id: node.prop_u32("reg").unwrap_or(0),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testutil::{cells, node, tree};
+
+    fn endpoint(name: &str, reg: Option<u32>, phandle: u32, remote: u32) -> Node {
+        let mut props = vec![
+            ("phandle", cells(&[phandle])),
+            ("remote-endpoint", cells(&[remote])),
+        ];
+        if let Some(reg) = reg {
+            props.push(("reg", cells(&[reg])));
+        }
+        node(name, props, vec![])
+    }
+
+    #[test]
+    fn pipeline() {
+        let root = node(
+            "",
+            vec![],
+            vec![
+                node(
+                    "lcdc",
+                    vec![],
+                    vec![node(
+                        "ports",
+                        vec![
+                            ("#address-cells", cells(&[1])),
+                            ("#size-cells", cells(&[0])),
+                        ],
+                        vec![
+                            node("port@0", vec![("reg", cells(&[0]))], vec![]),
+                            node(
+                                "port@1",
+                                vec![("reg", cells(&[1]))],
+                                vec![
+                                    endpoint("endpoint@0", Some(0), 1, 10),
+                                    endpoint("endpoint@1", Some(1), 2, 20),
+                                ],
+                            ),
+                        ],
+                    )],
+                ),
+                node(
+                    "hdmi",
+                    vec![],
+                    vec![node(
+                        "port",
+                        vec![],
+                        vec![endpoint("endpoint", None, 10, 1)],
+                    )],
+                ),
+                node(
+                    "panel",
+                    vec![],
+                    vec![node(
+                        "port",
+                        vec![],
+                        vec![endpoint("endpoint", None, 20, 10)],
+                    )],
+                ),
+            ],
+        );
+        let dt = tree(root);
+
+        let lcdc = dt.find("/lcdc").unwrap();
+        let ports = lcdc.ports();
+        assert_eq!(ports.len(), 2);
+        assert_eq!(ports[1].id, 1);
+        assert_eq!(lcdc.port(1).unwrap().endpoints().len(), 2);
+        assert!(lcdc.port(2).is_none());
+
+        let endpoints = dt.endpoints(lcdc).unwrap();
+        assert_eq!(endpoints[1].port, 1);
+        assert_eq!(endpoints[1].id, 1);
+        assert_eq!(endpoints[1].device.name, "lcdc");
+
+        let remote = dt.remote_endpoint(&endpoints[0]).unwrap();
+        assert_eq!(remote.device.name, "hdmi");
+        assert_eq!(remote.port, 0);
+        let devices: Vec<&str> = dt
+            .remote_devices(lcdc)
+            .unwrap()
+            .iter()
+            .map(|n| n.name.as_str())
+            .collect();
+        assert_eq!(devices, vec!["hdmi", "panel"]);
+
+        let links = dt.graph().unwrap();
+        assert_eq!(links.len(), 4);
+        assert!(links[0].bidirectional);
+        assert!(!links[1].bidirectional);
+        assert_eq!(links[3].local.device.name, "panel");
+        assert_eq!(links[3].remote.device.name, "hdmi");
+
+        assert!(matches!(dt.endpoint(lcdc), Err(GraphError::NotEndpoint)));
+    }
+}
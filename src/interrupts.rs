@@ -0,0 +1,398 @@
+//! Interrupt tree resolution
+//!
+//! Interrupts form a tree of their own, separate from the node hierarchy. A
+//! device's `interrupts` are routed to its interrupt parent (found through
+//! `interrupt-parent` or the device's ancestors), possibly translated by
+//! `interrupt-map` nexus nodes, until an `interrupt-controller` is reached.
+//! `interrupts-extended` names the parent of each entry explicitly.
+//!
+//! See section 2.4 of the devicetree specification and the kernel's
+//! `of_irq_parse_raw` for the algorithm.
+
+use phandle::PhandleError;
+use {DeviceTree, Node, PropError};
+
+/// An error encountered while resolving interrupts.
+#[derive(Debug)]
+pub enum InterruptError {
+    /// A property could not be read.
+    PropError(PropError),
+
+    /// An `interrupts-extended` entry could not be decoded.
+    PhandleError(PhandleError),
+
+    /// A phandle does not refer to any node in the tree.
+    InvalidPhandle(u32),
+
+    /// No interrupt parent could be found for the node.
+    NoParent,
+
+    /// An interrupt parent lacks `#interrupt-cells`.
+    MissingCells,
+
+    /// The `interrupts` list or an `interrupt-map` ended in the middle of an
+    /// entry.
+    Truncated,
+
+    /// None of the entries of an `interrupt-map` matched the specifier.
+    NoMapEntry,
+
+    /// An `interrupt-map` requires a unit address, but the device has no
+    /// `reg` property.
+    MissingReg,
+}
+
+/// An interrupt, as seen by the controller that handles it.
+#[derive(Debug, PartialEq)]
+pub struct Interrupt<'a> {
+    /// The interrupt controller node.
+    pub controller: &'a Node,
+
+    /// The interrupt specifier, in the controller's format.
+    pub specifier: Vec<u32>,
+}
+
+impl From<PropError> for InterruptError {
+    fn from(e: PropError) -> InterruptError {
+        InterruptError::PropError(e)
+    }
+}
+
+impl From<PhandleError> for InterruptError {
+    fn from(e: PhandleError) -> InterruptError {
+        InterruptError::PhandleError(e)
+    }
+}
+
+impl DeviceTree {
+    /// Find the interrupt parent of `node`.
+    ///
+    /// Follows `interrupt-parent` properties and the node's ancestors until a
+    /// node with `#interrupt-cells` is found. `None` if there is none, or if
+    /// the `interrupt-parent` properties form a cycle.
+    pub fn interrupt_parent(&self, node: &Node) -> Option<&Node> {
+        let mut cur = self.next_interrupt_parent(node)?;
+
+        // without a cycle, every node is visited at most once
+        for _ in 0..self.nodes().count() {
+            if cur.has_prop("#interrupt-cells") {
+                return Some(cur);
+            }
+            cur = self.next_interrupt_parent(cur)?;
+        }
+        None
+    }
+
+    /// Resolve all interrupts of `node` to their controllers.
+    ///
+    /// Uses `interrupts-extended` if present, `interrupts` otherwise. Nodes
+    /// without either property have no interrupts.
+    pub fn interrupts(&self, node: &Node) -> Result<Vec<Interrupt<'_>>, InterruptError> {
+        let unit_address = node.prop_cells("reg").ok();
+        let mut irqs = Vec::new();
+
+        if node.has_prop("interrupts-extended") {
+            for entry in node.phandle_args(self, "interrupts-extended", "#interrupt-cells")? {
+                let entry = entry?;
+                irqs.push(self.map_interrupt(entry.node, unit_address.as_deref(), entry.args)?);
+            }
+            return Ok(irqs);
+        }
+
+        let specifiers = match node.prop_cells("interrupts") {
+            Ok(specifiers) => specifiers,
+            Err(PropError::NotFound) => return Ok(irqs),
+            Err(e) => return Err(e.into()),
+        };
+
+        let parent = self
+            .interrupt_parent(node)
+            .ok_or(InterruptError::NoParent)?;
+        let size = parent
+            .prop_u32("#interrupt-cells")
+            .map_err(|_| InterruptError::MissingCells)? as usize;
+
+        if size == 0 {
+            return Ok(irqs);
+        }
+        if specifiers.len() % size != 0 {
+            return Err(InterruptError::Truncated);
+        }
+
+        for specifier in specifiers.chunks(size) {
+            irqs.push(self.map_interrupt(parent, unit_address.as_deref(), specifier.to_vec())?);
+        }
+
+        Ok(irqs)
+    }
+
+    /// Route an interrupt `specifier`, delivered to `parent` by a device
+    /// with the given `reg` (`unit_address`), to its interrupt controller.
+    ///
+    /// Every `interrupt-map` on the way translates the unit address and
+    /// specifier, masked by `interrupt-map-mask`. Fails with `NoParent` if
+    /// the interrupt parents form a cycle without a controller.
+    pub fn map_interrupt<'a>(
+        &'a self,
+        parent: &'a Node,
+        unit_address: Option<&[u32]>,
+        specifier: Vec<u32>,
+    ) -> Result<Interrupt<'a>, InterruptError> {
+        let mut ipar = parent;
+        let mut specifier = specifier;
+        let mut address: Vec<u32> = Vec::new();
+        let mut address_size = self.interrupt_address_cells(ipar) as usize;
+        for i in 0..address_size {
+            address.push(unit_address.and_then(|a| a.get(i).cloned()).unwrap_or(0));
+        }
+
+        // without a cycle, every node is visited at most once
+        for _ in 0..self.nodes().count() {
+            let map = match ipar.prop_cells("interrupt-map") {
+                Ok(map) => Some(map),
+                Err(PropError::NotFound) => None,
+                Err(e) => return Err(e.into()),
+            };
+
+            let map = match map {
+                Some(map) => map,
+                None => {
+                    if ipar.has_prop("interrupt-controller") {
+                        return Ok(Interrupt {
+                            controller: ipar,
+                            specifier,
+                        });
+                    }
+
+                    ipar = self
+                        .interrupt_parent(ipar)
+                        .ok_or(InterruptError::NoParent)?;
+                    continue;
+                }
+            };
+
+            if unit_address.is_none() && address_size != 0 {
+                return Err(InterruptError::MissingReg);
+            }
+
+            let mask = ipar.prop_cells("interrupt-map-mask").unwrap_or_default();
+            let child: Vec<u32> = address.iter().chain(specifier.iter()).cloned().collect();
+
+            let mut pos = 0;
+            let (new_parent, new_address, new_specifier) = loop {
+                if pos == map.len() {
+                    return Err(InterruptError::NoMapEntry);
+                }
+                if pos + child.len() + 1 > map.len() {
+                    return Err(InterruptError::Truncated);
+                }
+
+                let matches = child.iter().enumerate().all(|(i, c)| {
+                    (c ^ map[pos + i]) & mask.get(i).cloned().unwrap_or(0xffff_ffff) == 0
+                });
+                pos += child.len();
+
+                let phandle = map[pos];
+                pos += 1;
+
+                let new_parent = self
+                    .find_phandle(phandle)
+                    .ok_or(InterruptError::InvalidPhandle(phandle))?;
+                let new_size = new_parent
+                    .prop_u32("#interrupt-cells")
+                    .map_err(|_| InterruptError::MissingCells)?
+                    as usize;
+                let new_address_size = new_parent.prop_u32("#address-cells").unwrap_or(0) as usize;
+
+                let end = pos + new_address_size + new_size;
+                if end > map.len() {
+                    return Err(InterruptError::Truncated);
+                }
+
+                if matches {
+                    break (
+                        new_parent,
+                        map[pos..(pos + new_address_size)].to_vec(),
+                        map[(pos + new_address_size)..end].to_vec(),
+                    );
+                }
+
+                pos = end;
+            };
+
+            address_size = new_address.len();
+            address = new_address;
+            specifier = new_specifier;
+
+            // a map pointing to its own node terminates the walk
+            if ::core::ptr::eq(new_parent, ipar) {
+                return Ok(Interrupt {
+                    controller: ipar,
+                    specifier,
+                });
+            }
+            ipar = new_parent;
+        }
+        Err(InterruptError::NoParent)
+    }
+
+    /// The next step towards the interrupt parent: the target of
+    /// `interrupt-parent` or, if absent, the node's parent.
+    fn next_interrupt_parent(&self, node: &Node) -> Option<&Node> {
+        match node.prop_u32("interrupt-parent") {
+            Ok(phandle) => self.find_phandle(phandle),
+            Err(_) => self.parent_of(node),
+        }
+    }
+
+    /// `#address-cells` used for unit addresses in `interrupt-map` lookups.
+    ///
+    /// Like Linux, this falls back to the ancestors of `node` if it has no
+    /// `#address-cells` of its own, and to 2 if none is found.
+    pub(crate) fn interrupt_address_cells(&self, node: &Node) -> u32 {
+        let mut cur = Some(node);
+        while let Some(n) = cur {
+            if let Ok(cells) = n.prop_u32("#address-cells") {
+                return cells;
+            }
+            cur = self.parent_of(n);
+        }
+        2
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testutil::{cells, node, rpi, tree};
+
+    #[test]
+    fn interrupt_parent_from_root() {
+        let dt = rpi();
+
+        let uart = dt.find("/soc/uart@7e201000").unwrap();
+        let irqs = dt.interrupts(uart).unwrap();
+        assert_eq!(irqs.len(), 1);
+        assert_eq!(irqs[0].controller.name, "interrupt-controller@7e00b200");
+        assert_eq!(irqs[0].specifier, vec![2, 0x19]);
+
+        let dma = dt.find("/soc/dma@7e007000").unwrap();
+        assert_eq!(dt.interrupts(dma).unwrap().len(), 12);
+    }
+
+    #[test]
+    fn pci_interrupt_map() {
+        let root = node(
+            "",
+            vec![],
+            vec![
+                node(
+                    "intc",
+                    vec![
+                        ("phandle", cells(&[1])),
+                        ("interrupt-controller", vec![]),
+                        ("#interrupt-cells", cells(&[1])),
+                        ("#address-cells", cells(&[0])),
+                    ],
+                    vec![],
+                ),
+                node(
+                    "pci",
+                    vec![
+                        ("#address-cells", cells(&[3])),
+                        ("#size-cells", cells(&[2])),
+                        ("#interrupt-cells", cells(&[1])),
+                        ("interrupt-map-mask", cells(&[0xf800, 0, 0, 7])),
+                        (
+                            "interrupt-map",
+                            cells(&[0x0800, 0, 0, 1, 1, 5, 0x1000, 0, 0, 1, 1, 6]),
+                        ),
+                    ],
+                    vec![node(
+                        "dev@2,0",
+                        vec![
+                            ("reg", cells(&[0x1000, 0, 0, 0, 0])),
+                            ("interrupts", cells(&[1])),
+                        ],
+                        vec![],
+                    )],
+                ),
+                node("ext", vec![("interrupts-extended", cells(&[1, 9]))], vec![]),
+            ],
+        );
+        let dt = tree(root);
+
+        let dev = dt.find("/pci/dev@2,0").unwrap();
+        let irqs = dt.interrupts(dev).unwrap();
+        assert_eq!(irqs.len(), 1);
+        assert_eq!(irqs[0].controller.name, "intc");
+        assert_eq!(irqs[0].specifier, vec![6]);
+
+        let ext = dt.find("/ext").unwrap();
+        let irqs = dt.interrupts(ext).unwrap();
+        assert_eq!(irqs[0].controller.name, "intc");
+        assert_eq!(irqs[0].specifier, vec![9]);
+    }
+
+    #[test]
+    fn parent_cycles() {
+        let root = node(
+            "",
+            vec![],
+            vec![
+                node(
+                    "a",
+                    vec![("phandle", cells(&[1])), ("interrupt-parent", cells(&[2]))],
+                    vec![],
+                ),
+                node(
+                    "b",
+                    vec![("phandle", cells(&[2])), ("interrupt-parent", cells(&[1]))],
+                    vec![],
+                ),
+                // parents with #interrupt-cells, but no interrupt-controller
+                node(
+                    "c",
+                    vec![
+                        ("phandle", cells(&[3])),
+                        ("#interrupt-cells", cells(&[1])),
+                        ("interrupt-parent", cells(&[4])),
+                    ],
+                    vec![],
+                ),
+                node(
+                    "d",
+                    vec![
+                        ("phandle", cells(&[4])),
+                        ("#interrupt-cells", cells(&[1])),
+                        ("interrupt-parent", cells(&[3])),
+                    ],
+                    vec![],
+                ),
+                node(
+                    "dev",
+                    vec![
+                        ("interrupt-parent", cells(&[1])),
+                        ("interrupts", cells(&[5])),
+                    ],
+                    vec![],
+                ),
+                node(
+                    "dev2",
+                    vec![
+                        ("interrupt-parent", cells(&[3])),
+                        ("interrupts", cells(&[5])),
+                    ],
+                    vec![],
+                ),
+            ],
+        );
+        let dt = tree(root);
+
+        let dev = dt.find("/dev").unwrap();
+        assert!(dt.interrupt_parent(dev).is_none());
+        assert!(matches!(dt.interrupts(dev), Err(InterruptError::NoParent)));
+        let dev2 = dt.find("/dev2").unwrap();
+        assert!(matches!(dt.interrupts(dev2), Err(InterruptError::NoParent)));
+    }
+}
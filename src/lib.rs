@@ -31,10 +31,43 @@
 //! ```
 
 extern crate core;
-
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+
+pub mod canonical;
+pub mod chosen;
+pub mod clocks;
+pub mod compatible;
+pub mod cpus;
+pub mod fdt;
+pub mod gpio;
+pub mod graph;
+pub mod interrupts;
+pub mod memory;
+pub mod pci;
+pub mod phandle;
+pub mod pinctrl;
+pub mod query;
+pub mod regulator;
+pub mod reserved_memory;
+#[cfg(feature = "serde")]
+mod serialization;
+pub mod status;
+pub mod store;
+mod stringtable;
 pub mod util;
+pub mod value;
+pub mod walk;
+pub mod writer;
+
+#[cfg(test)]
+mod testutil;
 
-use core::str;
+use core::{ptr, str};
+use store::{BlockOrder, StoreOptions};
+use stringtable::StringTable;
 use util::{align, SliceRead, SliceReadError, VecWrite, VecWriteError};
 
 const MAGIC_NUMBER: u32 = 0xd00dfeed;
@@ -43,8 +76,11 @@ const COMPAT_VERSION: u32 = 16;
 const OF_DT_BEGIN_NODE: u32 = 0x00000001;
 const OF_DT_END_NODE: u32 = 0x00000002;
 const OF_DT_PROP: u32 = 0x00000003;
+const OF_DT_NOP: u32 = 0x00000004;
 const OF_DT_END: u32 = 0x00000009;
 
+static ZEROS: [u8; 64] = [0; 64];
+
 /// An error describe parsing problems when creating device trees.
 #[derive(Debug)]
 pub enum DeviceTreeError {
@@ -69,14 +105,26 @@ pub enum DeviceTreeError {
     /// The device tree version is not supported by this library.
     VersionNotSupported,
 
+    /// The tree cannot be stored in the requested version, e.g. a non-zero
+    /// `boot_cpuid_phys` in version 1, or `last_comp_version` is not at most
+    /// the version.
+    NotRepresentable,
+
     /// The device tree structure could not be serialized to DTB
     VecWriteError(VecWriteError),
+
+    /// The buffer passed to `store_into()` is smaller than the serialized
+    /// device tree, which needs the given number of bytes.
+    BufferTooSmall(usize),
 }
 
 /// Device tree structure.
-#[derive(Debug, PartialEq)]
+///
+/// Trees compare equal if their contents do; the layout is ignored.
+#[derive(Debug)]
 pub struct DeviceTree {
-    /// Version, as indicated by version header
+    /// Version, as indicated by version header. `store()` writes this
+    /// version unless `StoreOptions::version` says otherwise.
     pub version: u32,
 
     /// The number of the CPU the system boots from
@@ -88,6 +136,113 @@ pub struct DeviceTree {
 
     /// The root node.
     pub root: Node,
+
+    /// The layout of the blob the tree was loaded from, see `layout()`.
+    layout: Option<Layout>,
+}
+
+/// Details of a loaded blob that are not part of the tree itself.
+#[derive(Clone, Debug)]
+pub struct Layout {
+    total_size: usize,
+    off_dt_struct: usize,
+    off_dt_strings: usize,
+    off_mem_rsvmap: usize,
+    last_comp_version: u32,
+
+    /// The size of the structure block, including the `END` tag.
+    struct_size: usize,
+
+    /// The strings block.
+    strings: Vec<u8>,
+
+    /// The name offset of every property, in tree order.
+    name_offsets: Vec<u32>,
+
+    /// The `NOP` tags of the structure block, as the number of other tags
+    /// before each of them.
+    nops: Vec<u32>,
+
+    /// The number of tags other than `NOP` read so far.
+    tags: u32,
+}
+
+impl Layout {
+    /// The `totalsize` of the blob.
+    pub fn total_size(&self) -> usize {
+        self.total_size
+    }
+
+    /// The offset of the structure block.
+    pub fn off_dt_struct(&self) -> usize {
+        self.off_dt_struct
+    }
+
+    /// The offset of the strings block.
+    pub fn off_dt_strings(&self) -> usize {
+        self.off_dt_strings
+    }
+
+    /// The offset of the memory reservation block.
+    pub fn off_mem_rsvmap(&self) -> usize {
+        self.off_mem_rsvmap
+    }
+
+    /// The `last_comp_version` field of the header.
+    pub fn last_comp_version(&self) -> u32 {
+        self.last_comp_version
+    }
+
+    /// The order of the structure and strings blocks.
+    pub fn block_order(&self) -> BlockOrder {
+        if self.off_dt_strings < self.off_dt_struct {
+            BlockOrder::StringsStruct
+        } else {
+            BlockOrder::StructStrings
+        }
+    }
+
+    /// Skip the `NOP` tags at `pos`, returning the position of the next
+    /// tag.
+    fn skip_nops(&mut self, buffer: &[u8], mut pos: usize) -> Result<usize, DeviceTreeError> {
+        while buffer.read_be_u32(pos)? == OF_DT_NOP {
+            self.nops.push(self.tags);
+            pos += 4;
+        }
+        Ok(pos)
+    }
+}
+
+/// State of writing the structure block: the `NOP` tags of a loaded blob
+/// are written back at their original place, and versions before 16 need
+/// the full path of every node.
+struct Tags<'a> {
+    nops: &'a [u32],
+    tags: u32,
+    version: u32,
+    path: String,
+}
+
+impl<'a> Tags<'a> {
+    fn new(layout: Option<&'a Layout>, version: u32) -> Tags<'a> {
+        Tags {
+            nops: layout.map_or(&[][..], |l| &l.nops[..]),
+            tags: 0,
+            version,
+            path: String::new(),
+        }
+    }
+
+    /// Called before writing any other tag.
+    fn before_tag<W: VecWrite>(&mut self, out: &mut W) -> Result<(), DeviceTreeError> {
+        while self.nops.first() == Some(&self.tags) {
+            let len = out.offset();
+            out.write_be_u32(len, OF_DT_NOP)?;
+            self.nops = &self.nops[1..];
+        }
+        self.tags += 1;
+        Ok(())
+    }
 }
 
 /// A single node in the device tree.
@@ -109,6 +264,18 @@ pub enum PropError {
     Utf8Error,
     Missing0,
     SliceReadError(SliceReadError),
+
+    /// A number does not fit into the cells it is read from or written to.
+    Overflow,
+}
+
+impl PartialEq for DeviceTree {
+    fn eq(&self, other: &DeviceTree) -> bool {
+        self.version == other.version
+            && self.boot_cpuid_phys == other.boot_cpuid_phys
+            && self.reserved == other.reserved
+            && self.root == other.root
+    }
 }
 
 impl From<SliceReadError> for DeviceTreeError {
@@ -129,62 +296,6 @@ impl From<str::Utf8Error> for DeviceTreeError {
     }
 }
 
-#[cfg(feature = "string-dedup")]
-mod advancedstringtable {
-    use std::collections::HashMap;
-
-    pub struct StringTable {
-        pub buffer: Vec<u8>,
-        index: HashMap<String, u32>,
-    }
-
-    impl StringTable {
-        pub fn new() -> StringTable {
-            StringTable {
-                buffer: Vec::new(),
-                index: HashMap::new(),
-            }
-        }
-
-        pub fn add_string(&mut self, val: &str) -> u32 {
-            if let Some(offset) = self.index.get(val) {
-                return *offset;
-            }
-            let offset = self.buffer.len() as u32;
-            self.buffer.extend(val.bytes());
-            self.buffer.push(0);
-            self.index.insert(val.to_string(), offset);
-            offset
-        }
-    }
-}
-
-#[cfg(not(feature = "string-dedup"))]
-mod stringtable {
-    pub struct StringTable {
-        pub buffer: Vec<u8>,
-    }
-
-    impl StringTable {
-        pub fn new() -> StringTable {
-            StringTable { buffer: Vec::new() }
-        }
-
-        pub fn add_string(&mut self, val: &str) -> u32 {
-            let offset = self.buffer.len();
-            self.buffer.extend(val.bytes());
-            self.buffer.push(0);
-            offset as u32
-        }
-    }
-}
-
-#[cfg(not(feature = "string-dedup"))]
-use stringtable::StringTable;
-
-#[cfg(feature = "string-dedup")]
-use advancedstringtable::StringTable;
-
 impl DeviceTree {
     //! Load a device tree from a memory buffer.
     pub fn load(buffer: &[u8]) -> Result<DeviceTree, DeviceTreeError> {
@@ -206,34 +317,36 @@ impl DeviceTree {
         // // version 17 fields
         // 36  size_dt_struct: u32,
 
-        if try!(buffer.read_be_u32(0)) != MAGIC_NUMBER {
+        if buffer.read_be_u32(0)? != MAGIC_NUMBER {
             return Err(DeviceTreeError::InvalidMagicNumber);
         }
 
         // check total size
-        if try!(buffer.read_be_u32(4)) as usize != buffer.len() {
+        if buffer.read_be_u32(4)? as usize != buffer.len() {
             return Err(DeviceTreeError::SizeMismatch);
         }
 
         // check version
-        let version = try!(buffer.read_be_u32(20));
-        if version != SUPPORTED_VERSION {
+        let version = buffer.read_be_u32(20)?;
+        if version != SUPPORTED_VERSION && version != COMPAT_VERSION {
             return Err(DeviceTreeError::VersionNotSupported);
         }
 
-        let off_dt_struct = try!(buffer.read_be_u32(8)) as usize;
-        let off_dt_strings = try!(buffer.read_be_u32(12)) as usize;
-        let off_mem_rsvmap = try!(buffer.read_be_u32(16)) as usize;
-        let boot_cpuid_phys = try!(buffer.read_be_u32(28));
+        let off_dt_struct = buffer.read_be_u32(8)? as usize;
+        let off_dt_strings = buffer.read_be_u32(12)? as usize;
+        let off_mem_rsvmap = buffer.read_be_u32(16)? as usize;
+        let last_comp_version = buffer.read_be_u32(24)?;
+        let boot_cpuid_phys = buffer.read_be_u32(28)?;
+        let size_dt_strings = buffer.read_be_u32(32)? as usize;
 
         // load reserved memory list
         let mut reserved = Vec::new();
         let mut pos = off_mem_rsvmap;
 
         loop {
-            let offset = try!(buffer.read_be_u64(pos));
+            let offset = buffer.read_be_u64(pos)?;
             pos += 8;
-            let size = try!(buffer.read_be_u64(pos));
+            let size = buffer.read_be_u64(pos)?;
             pos += 8;
 
             reserved.push((offset, size));
@@ -243,94 +356,307 @@ impl DeviceTree {
             }
         }
 
-        let (_, root) = try!(Node::load(buffer, off_dt_struct, off_dt_strings));
+        let mut layout = Layout {
+            total_size: buffer.len(),
+            off_dt_struct,
+            off_dt_strings,
+            off_mem_rsvmap,
+            last_comp_version,
+            struct_size: 0,
+            strings: buffer
+                .get(off_dt_strings..(off_dt_strings + size_dt_strings))
+                .ok_or(DeviceTreeError::SizeMismatch)?
+                .to_owned(),
+            name_offsets: Vec::new(),
+            nops: Vec::new(),
+            tags: 0,
+        };
+        let start = layout.skip_nops(buffer, off_dt_struct)?;
+        let (pos, root) = Node::load(buffer, start, off_dt_strings, &mut layout)?;
+        let end = layout.skip_nops(buffer, pos)?;
+        layout.struct_size = end + 4 - off_dt_struct;
 
         Ok(DeviceTree {
-            version: version,
-            boot_cpuid_phys: boot_cpuid_phys,
-            reserved: reserved,
-            root: root,
+            version,
+            boot_cpuid_phys,
+            reserved,
+            root,
+            layout: Some(layout),
         })
     }
 
+    /// A version 17 tree with the given root node, booting from CPU 0 and
+    /// without memory reservations. Like in loaded trees, `reserved` ends
+    /// with the `(0, 0)` terminator.
+    pub fn new(root: Node) -> DeviceTree {
+        DeviceTree {
+            version: SUPPORTED_VERSION,
+            boot_cpuid_phys: 0,
+            reserved: vec![(0, 0)],
+            root,
+            layout: None,
+        }
+    }
+
+    /// Find a node by path.
+    ///
+    /// Absolute paths start at the root node. Otherwise the first path
+    /// component is looked up in `/aliases`, e.g. `serial0` or
+    /// `mmc0/partition@1`.
     pub fn find<'a>(&'a self, path: &str) -> Option<&'a Node> {
-        // we only find root nodes on the device tree
-        if !path.starts_with('/') {
-            return None;
+        if let Some(path) = path.strip_prefix('/') {
+            return self.root.find(path);
         }
 
-        self.root.find(&path[1..])
+        let (alias, rest) = split_path(path);
+        let target = self.alias(alias)?.strip_prefix('/')?;
+        self.root.find(target)?.find(rest)
+    }
+
+    /// Find a node by path for modification, see `find`.
+    pub fn find_mut<'a>(&'a mut self, path: &str) -> Option<&'a mut Node> {
+        if let Some(path) = path.strip_prefix('/') {
+            return self.root.find_mut(path);
+        }
+
+        let (alias, rest) = split_path(path);
+        let target = self.alias(alias)?.strip_prefix('/')?.to_owned();
+        self.root.find_mut(&target)?.find_mut(rest)
+    }
+
+    /// Look up `name` in `/aliases`, returning the path it stands for.
+    pub fn alias(&self, name: &str) -> Option<&str> {
+        self.root.find("aliases")?.prop_str(name).ok()
+    }
+
+    /// The layout of the blob the tree was loaded from, reused by `store()`
+    /// where the tree still matches it. `None` for trees built in code.
+    pub fn layout(&self) -> Option<&Layout> {
+        self.layout.as_ref()
+    }
+
+    /// Forget the layout of the loaded blob, so that `store()` produces a
+    /// fresh, minimal blob.
+    pub fn clear_layout(&mut self) {
+        self.layout = None;
     }
 
-    pub fn store<'a>(&'a self) -> Result<Vec<u8>, DeviceTreeError> {
-        let mut dtb = Vec::new();
-        let mut strings = StringTable::new();
+    /// Find the node whose `phandle` (or legacy `linux,phandle`) property
+    /// equals `phandle`.
+    pub fn find_phandle(&self, phandle: u32) -> Option<&Node> {
+        self.root.find_phandle(phandle)
+    }
 
+    /// Decode the `reg` property of `node`, using the `#address-cells` and
+    /// `#size-cells` of its parent.
+    pub fn reg(&self, node: &Node) -> Result<Vec<(u64, u64)>, PropError> {
+        self.prop_reg(node, "reg")
+    }
+
+    /// Decode a `reg`-like property of `node`, see `reg`.
+    pub fn prop_reg(&self, node: &Node, name: &str) -> Result<Vec<(u64, u64)>, PropError> {
+        let (address_cells, size_cells) = match self.parent_of(node) {
+            Some(parent) => (parent.address_cells(), parent.size_cells()),
+            None => (2, 1),
+        };
+        node.prop_reg(name, address_cells, size_cells)
+    }
+
+    /// Find the parent of `node`, which must be a node inside this tree.
+    ///
+    /// Nodes do not store a link to their parent, so this searches the tree
+    /// for the node by identity.
+    pub fn parent_of<'a>(&'a self, node: &Node) -> Option<&'a Node> {
+        self.root.parent_of(node)
+    }
+
+    pub fn store(&self) -> Result<Vec<u8>, DeviceTreeError> {
+        self.store_with(&StoreOptions::default())
+    }
+
+    /// The exact size in bytes of the blob produced by `store()`.
+    pub fn measure(&self) -> Result<usize, DeviceTreeError> {
+        self.measure_with(&StoreOptions::default())
+    }
+
+    /// Serialize into `buf`, returning the number of bytes written.
+    ///
+    /// This does not allocate for trees without a layout stored as version
+    /// 16 or 17, unless the `string-dedup` feature is enabled. Loaded trees
+    /// index the names in their strings block, and older versions build the
+    /// full path of every node. If the blob does not fit,
+    /// `DeviceTreeError::BufferTooSmall` is returned and `buf` is left
+    /// untouched.
+    pub fn store_into(&self, buf: &mut [u8]) -> Result<usize, DeviceTreeError> {
+        self.store_into_with(buf, &StoreOptions::default())
+    }
+
+    fn write<W: VecWrite>(
+        &self,
+        dtb: &mut W,
+        options: &StoreOptions,
+    ) -> Result<(), DeviceTreeError> {
         // Magic
-        let len = dtb.len();
-        try!(dtb.write_be_u32(len, MAGIC_NUMBER));
-
-        let size_off = dtb.len();
-        try!(dtb.write_be_u32(size_off, 0)); // Fill in size later
-        let off_dt_struct = dtb.len();
-        try!(dtb.write_be_u32(off_dt_struct, 0)); // Fill in off_dt_struct later
-        let off_dt_strings = dtb.len();
-        try!(dtb.write_be_u32(off_dt_strings, 0)); // Fill in off_dt_strings later
-        let off_mem_rsvmap = dtb.len();
-        try!(dtb.write_be_u32(off_mem_rsvmap, 0)); // Fill in off_mem_rsvmap later
+        let len = dtb.offset();
+        dtb.write_be_u32(len, MAGIC_NUMBER)?;
+
+        let size_off = dtb.offset();
+        dtb.write_be_u32(size_off, 0)?; // Fill in size later
+        let off_dt_struct = dtb.offset();
+        dtb.write_be_u32(off_dt_struct, 0)?; // Fill in off_dt_struct later
+        let off_dt_strings = dtb.offset();
+        dtb.write_be_u32(off_dt_strings, 0)?; // Fill in off_dt_strings later
+        let off_mem_rsvmap = dtb.offset();
+        dtb.write_be_u32(off_mem_rsvmap, 0)?; // Fill in off_mem_rsvmap later
 
         // Version
-        let len = dtb.len();
-        try!(dtb.write_be_u32(len, SUPPORTED_VERSION));
+        let version = options.version.unwrap_or(self.version);
+        let len = dtb.offset();
+        dtb.write_be_u32(len, version)?;
         // Last comp version
-        let len = dtb.len();
-        try!(dtb.write_be_u32(len, COMPAT_VERSION));
-        // boot_cpuid_phys
-        let len = dtb.len();
-        try!(dtb.write_be_u32(len, self.boot_cpuid_phys));
+        let len = dtb.offset();
+        let last_comp_version = options.last_comp_version.unwrap_or_else(|| {
+            self.layout
+                .as_ref()
+                .map(|l| l.last_comp_version)
+                .filter(|&v| v <= version)
+                .unwrap_or(if version < 16 { 1 } else { COMPAT_VERSION })
+        });
+        if last_comp_version == 0 || last_comp_version > version {
+            return Err(DeviceTreeError::NotRepresentable);
+        }
+        dtb.write_be_u32(len, last_comp_version)?;
+
+        // The fields added by later versions
+        let mut off_size_strings = None;
+        let mut off_size_struct = None;
+        match version {
+            1 if self.boot_cpuid_phys != 0 => return Err(DeviceTreeError::NotRepresentable),
+            1 => (),
+            2 | 3 | 16 | 17 => {
+                let len = dtb.offset();
+                dtb.write_be_u32(len, self.boot_cpuid_phys)?;
+            }
+            _ => return Err(DeviceTreeError::VersionNotSupported),
+        }
+        if version >= 3 {
+            let len = dtb.offset();
+            dtb.write_be_u32(len, 0)?; // Fill in size_dt_strings later
+            off_size_strings = Some(len);
+        }
+        if version >= 17 {
+            let len = dtb.offset();
+            dtb.write_be_u32(len, 0)?; // Fill in size_dt_struct later
+            off_size_struct = Some(len);
+        }
 
-        let off_size_strings = dtb.len();
-        try!(dtb.write_be_u32(off_size_strings, 0)); // Fill in size_dt_strings later
-        let off_size_struct = dtb.len();
-        try!(dtb.write_be_u32(off_size_struct, 0)); // Fill in size_dt_struct later
+        // Blocks are placed at their original offsets, unless they moved
+        // due to changes or a different block order
+        let order = options.block_order.unwrap_or_else(|| {
+            self.layout
+                .as_ref()
+                .map_or(BlockOrder::StructStrings, Layout::block_order)
+        });
+        let layout = self.layout.as_ref().filter(|l| l.block_order() == order);
 
         // Memory Reservation Block
-        try!(dtb.pad(8));
-        let len = dtb.len();
-        try!(dtb.write_be_u32(off_mem_rsvmap, len as u32));
+        dtb.pad(8)?;
+        fill(dtb, layout.map_or(0, |l| l.off_mem_rsvmap))?;
+        let len = dtb.offset();
+        dtb.write_be_u32(off_mem_rsvmap, len as u32)?;
         for reservation in self.reserved.iter() {
             // address
-            let len = dtb.len();
-            try!(dtb.write_be_u64(len, reservation.0));
+            let len = dtb.offset();
+            dtb.write_be_u64(len, reservation.0)?;
             // size
-            let len = dtb.len();
-            try!(dtb.write_be_u64(len, reservation.1));
+            let len = dtb.offset();
+            dtb.write_be_u64(len, reservation.1)?;
         }
 
-        // Structure Block
-        try!(dtb.pad(4));
-        let structure_start = dtb.len();
-        try!(dtb.write_be_u32(off_dt_struct, structure_start as u32));
-        try!(self.root.store(&mut dtb, &mut strings));
-
-        try!(dtb.pad(4));
-        let len = dtb.len();
-        try!(dtb.write_be_u32(len, OF_DT_END));
+        // the name offsets are known up front, so the blocks can be written
+        // in either order
+        let compact = cfg!(feature = "string-dedup");
+        let mut strings = StringTable::new(&self.root, self.layout.as_ref(), compact);
+        let struct_start = layout.map_or(0, |l| l.off_dt_struct);
+        let strings_start = layout.map_or(0, |l| l.off_dt_strings);
+        let struct_size = match order {
+            BlockOrder::StructStrings => {
+                fill(dtb, struct_start)?;
+                let size =
+                    self.write_struct(dtb, &mut strings, version, off_dt_struct, off_size_struct)?;
+                fill(dtb, strings_start)?;
+                self.write_strings(dtb, &strings, off_dt_strings, off_size_strings)?;
+                size
+            }
+            BlockOrder::StringsStruct => {
+                fill(dtb, strings_start)?;
+                self.write_strings(dtb, &strings, off_dt_strings, off_size_strings)?;
+                fill(dtb, struct_start)?;
+                self.write_struct(dtb, &mut strings, version, off_dt_struct, off_size_struct)?
+            }
+        };
+
+        // Free space. The size of the loaded blob is kept unless the tree
+        // shrank, so that removing nodes gives a smaller blob.
+        let kept_size = self
+            .layout
+            .as_ref()
+            .filter(|l| struct_size >= l.struct_size && strings.size() >= l.strings.len())
+            .map_or(0, |l| l.total_size);
+        let mut total = dtb.offset() + options.padding;
+        total = total.max(options.min_size);
+        total = total.max(kept_size);
+        total = align(total, options.alignment.max(1));
+        fill(dtb, total)?;
+
+        let len = dtb.offset();
+        dtb.write_be_u32(size_off, len as u32)?;
 
-        let len = dtb.len();
-        try!(dtb.write_be_u32(off_size_struct, (len - structure_start) as u32));
-        try!(dtb.write_be_u32(off_size_strings, strings.buffer.len() as u32));
+        Ok(())
+    }
 
-        // Strings Block
-        try!(dtb.pad(4));
-        let len = dtb.len();
-        try!(dtb.write_be_u32(off_dt_strings, len as u32));
-        dtb.extend_from_slice(&strings.buffer);
+    fn write_struct<W: VecWrite>(
+        &self,
+        dtb: &mut W,
+        strings: &mut StringTable,
+        version: u32,
+        off_dt_struct: usize,
+        off_size_struct: Option<usize>,
+    ) -> Result<usize, DeviceTreeError> {
+        dtb.pad(4)?;
+        let structure_start = dtb.offset();
+        dtb.write_be_u32(off_dt_struct, structure_start as u32)?;
+        let mut tags = Tags::new(self.layout.as_ref(), version);
+        self.root.store_tags(dtb, strings, &mut tags)?;
+
+        dtb.pad(4)?;
+        tags.before_tag(dtb)?;
+        let len = dtb.offset();
+        dtb.write_be_u32(len, OF_DT_END)?;
+
+        let size = dtb.offset() - structure_start;
+        if let Some(off_size_struct) = off_size_struct {
+            dtb.write_be_u32(off_size_struct, size as u32)?;
+        }
+        Ok(size)
+    }
 
-        let len = dtb.len();
-        try!(dtb.write_be_u32(size_off, len as u32));
+    fn write_strings<W: VecWrite>(
+        &self,
+        dtb: &mut W,
+        strings: &StringTable,
+        off_dt_strings: usize,
+        off_size_strings: Option<usize>,
+    ) -> Result<(), DeviceTreeError> {
+        if let Some(off_size_strings) = off_size_strings {
+            dtb.write_be_u32(off_size_strings, strings.size() as u32)?;
+        }
 
-        Ok(dtb)
+        dtb.pad(4)?;
+        let len = dtb.offset();
+        dtb.write_be_u32(off_dt_strings, len as u32)?;
+        strings.write(dtb, &self.root)?;
+        Ok(())
     }
 }
 
@@ -339,32 +665,42 @@ impl Node {
         buffer: &[u8],
         start: usize,
         off_dt_strings: usize,
+        layout: &mut Layout,
     ) -> Result<(usize, Node), DeviceTreeError> {
         // check for DT_BEGIN_NODE
-        if try!(buffer.read_be_u32(start)) != OF_DT_BEGIN_NODE {
+        if buffer.read_be_u32(start)? != OF_DT_BEGIN_NODE {
             return Err(DeviceTreeError::ParseError(start));
         }
 
-        let raw_name = try!(buffer.read_bstring0(start + 4));
+        layout.tags += 1;
+
+        let raw_name = buffer.read_bstring0(start + 4)?;
 
         // read all the props
         let mut pos = align(start + 4 + raw_name.len() + 1, 4);
 
         let mut props = Vec::new();
 
-        while try!(buffer.read_be_u32(pos)) == OF_DT_PROP {
-            let val_size = try!(buffer.read_be_u32(pos + 4)) as usize;
-            let name_offset = try!(buffer.read_be_u32(pos + 8)) as usize;
+        loop {
+            pos = layout.skip_nops(buffer, pos)?;
+            if buffer.read_be_u32(pos)? != OF_DT_PROP {
+                break;
+            }
+            layout.tags += 1;
+
+            let val_size = buffer.read_be_u32(pos + 4)? as usize;
+            let name_offset = buffer.read_be_u32(pos + 8)? as usize;
 
             // get value slice
             let val_start = pos + 12;
             let val_end = val_start + val_size;
-            let val = try!(buffer.subslice(val_start, val_end));
+            let val = buffer.subslice(val_start, val_end)?;
 
             // lookup name in strings table
-            let prop_name = try!(buffer.read_bstring0(off_dt_strings + name_offset));
+            let prop_name = buffer.read_bstring0(off_dt_strings + name_offset)?;
+            layout.name_offsets.push(name_offset as u32);
 
-            props.push((try!(str::from_utf8(prop_name)).to_owned(), val.to_owned()));
+            props.push((str::from_utf8(prop_name)?.to_owned(), val.to_owned()));
 
             pos = align(val_end, 4);
         }
@@ -372,31 +708,36 @@ impl Node {
         // finally, parse children
         let mut children = Vec::new();
 
-        while try!(buffer.read_be_u32(pos)) == OF_DT_BEGIN_NODE {
-            let (new_pos, child_node) = try!(Node::load(buffer, pos, off_dt_strings));
+        loop {
+            pos = layout.skip_nops(buffer, pos)?;
+            if buffer.read_be_u32(pos)? != OF_DT_BEGIN_NODE {
+                break;
+            }
+            let (new_pos, child_node) = Node::load(buffer, pos, off_dt_strings, layout)?;
             pos = new_pos;
 
             children.push(child_node);
         }
 
-        if try!(buffer.read_be_u32(pos)) != OF_DT_END_NODE {
+        if buffer.read_be_u32(pos)? != OF_DT_END_NODE {
             return Err(DeviceTreeError::ParseError(pos));
         }
+        layout.tags += 1;
 
         pos += 4;
 
         Ok((
             pos,
             Node {
-                name: try!(str::from_utf8(raw_name)).to_owned(),
-                props: props,
-                children: children,
+                name: str::from_utf8(raw_name)?.to_owned(),
+                props,
+                children,
             },
         ))
     }
 
     pub fn find<'a>(&'a self, path: &str) -> Option<&'a Node> {
-        if path == "" {
+        if path.is_empty() {
             return Some(self);
         }
 
@@ -409,40 +750,101 @@ impl Node {
                 // we know that the first char of slashed is a '/'
                 let subpath = &r[1..];
 
-                for child in self.children.iter() {
-                    if child.name == l {
-                        return child.find(subpath);
-                    }
-                }
+                self.child(l).and_then(|child| child.find(subpath))
+            }
+            None => self.child(path),
+        }
+    }
+
+    pub fn find_mut<'a>(&'a mut self, path: &str) -> Option<&'a mut Node> {
+        if path.is_empty() {
+            return Some(self);
+        }
+
+        let (name, subpath) = split_path(path);
 
-                // no matching child found
-                None
+        self.child_mut(name)
+            .and_then(|child| child.find_mut(subpath))
+    }
+
+    /// Find a direct child by name.
+    ///
+    /// An exact match is preferred. Otherwise, a name without a unit address
+    /// (`serial`) matches a child with that base name (`serial@7e201000`) and
+    /// a bare unit address (`@7e201000`) matches a child with that unit
+    /// address, as long as only one child matches.
+    pub fn child<'a>(&'a self, name: &str) -> Option<&'a Node> {
+        self.child_index(name).map(|idx| &self.children[idx])
+    }
+
+    /// Find a direct child by name for modification, see `child`.
+    pub fn child_mut<'a>(&'a mut self, name: &str) -> Option<&'a mut Node> {
+        self.child_index(name)
+            .map(move |idx| &mut self.children[idx])
+    }
+
+    fn child_index(&self, name: &str) -> Option<usize> {
+        if let Some(idx) = self.children.iter().position(|n| n.name == name) {
+            return Some(idx);
+        }
+
+        let (base, unit) = split_name(name);
+        let mut matches = self.children.iter().enumerate().filter(|&(_, n)| {
+            let (child_base, child_unit) = split_name(&n.name);
+            match unit {
+                None => child_base == base,
+                Some(unit) => base.is_empty() && child_unit == Some(unit),
             }
-            None => self.children.iter().find(|n| n.name == path),
+        });
+
+        let (idx, _) = matches.next()?;
+        if matches.next().is_some() {
+            // ambiguous
+            return None;
         }
+        Some(idx)
     }
 
-    pub fn has_prop(&self, name: &str) -> bool {
-        if let Some(_) = self.prop_raw(name) {
-            true
-        } else {
-            false
+    /// The node name without its unit address, e.g. `serial` for
+    /// `serial@7e201000`.
+    pub fn base_name(&self) -> &str {
+        split_name(&self.name).0
+    }
+
+    /// The unit address part of the node name, e.g. `7e201000` for
+    /// `serial@7e201000`.
+    pub fn unit_address(&self) -> Option<&str> {
+        split_name(&self.name).1
+    }
+
+    /// The unit address derived from the first address in `reg`, given the
+    /// `#address-cells` of the parent node.
+    pub fn reg_unit_address(&self, address_cells: u32) -> Option<String> {
+        let reg = self.prop_cells("reg").ok()?;
+        let address_cells = address_cells as usize;
+        if address_cells == 0 || reg.len() < address_cells {
+            return None;
         }
+        Some(format_unit_address(&reg[..address_cells]))
+    }
+
+    pub fn has_prop(&self, name: &str) -> bool {
+        self.prop_raw(name).is_some()
     }
 
     pub fn prop_str<'a>(&'a self, name: &str) -> Result<&'a str, PropError> {
-        let raw = try!(self.prop_raw(name).ok_or(PropError::NotFound));
+        let raw = self.prop_raw(name).ok_or(PropError::NotFound)?;
 
         let l = raw.len();
         if l < 1 || raw[l - 1] != 0 {
             return Err(PropError::Missing0);
         }
 
-        Ok(try!(str::from_utf8(&raw[..(l - 1)])))
+        Ok(str::from_utf8(&raw[..(l - 1)])?)
     }
 
     pub fn prop_raw<'a>(&'a self, name: &str) -> Option<&'a Vec<u8>> {
-        for &(ref key, ref val) in self.props.iter() {
+        for (key, val) in self.props.iter() {
             if key == name {
                 return Some(val);
             }
@@ -451,58 +853,346 @@ impl Node {
     }
 
     pub fn prop_u64(&self, name: &str) -> Result<u64, PropError> {
-        let raw = try!(self.prop_raw(name).ok_or(PropError::NotFound));
+        let raw = self.prop_raw(name).ok_or(PropError::NotFound)?;
 
-        Ok(try!(raw.as_slice().read_be_u64(0)))
+        Ok(raw.as_slice().read_be_u64(0)?)
     }
 
     pub fn prop_u32(&self, name: &str) -> Result<u32, PropError> {
-        let raw = try!(self.prop_raw(name).ok_or(PropError::NotFound));
+        let raw = self.prop_raw(name).ok_or(PropError::NotFound)?;
 
-        Ok(try!(raw.as_slice().read_be_u32(0)))
+        Ok(raw.as_slice().read_be_u32(0)?)
     }
 
-    pub fn store(
+    /// Read a property holding a single 32 or 64 bit integer, depending on
+    /// its size.
+    pub fn prop_uint(&self, name: &str) -> Result<u64, PropError> {
+        let raw = self.prop_raw(name).ok_or(PropError::NotFound)?;
+
+        match raw.len() {
+            4 => Ok(u64::from(raw.as_slice().read_be_u32(0)?)),
+            8 => Ok(raw.as_slice().read_be_u64(0)?),
+            _ => Err(PropError::SliceReadError(
+                SliceReadError::UnexpectedEndOfInput,
+            )),
+        }
+    }
+
+    /// Read a property as a list of big-endian 32 bit cells.
+    pub fn prop_cells(&self, name: &str) -> Result<Vec<u32>, PropError> {
+        let raw = self.prop_raw(name).ok_or(PropError::NotFound)?;
+        bytes_to_cells(raw)
+    }
+
+    /// Read a property as a list of `(address, size)` pairs, encoded with
+    /// the given number of cells, such as `reg`.
+    pub fn prop_reg(
         &self,
-        structure: &mut Vec<u8>,
+        name: &str,
+        address_cells: u32,
+        size_cells: u32,
+    ) -> Result<Vec<(u64, u64)>, PropError> {
+        let cells = self.prop_cells(name)?;
+        let (ac, sc) = (address_cells as usize, size_cells as usize);
+
+        if ac + sc == 0 {
+            return Ok(Vec::new());
+        }
+        if cells.len() % (ac + sc) != 0 {
+            return Err(PropError::SliceReadError(
+                SliceReadError::UnexpectedEndOfInput,
+            ));
+        }
+
+        let mut regions = Vec::new();
+        for entry in cells.chunks(ac + sc) {
+            regions.push((cells_to_u64(&entry[..ac])?, cells_to_u64(&entry[ac..])?));
+        }
+        Ok(regions)
+    }
+
+    /// Store `(address, size)` pairs in property `name`, encoded with the
+    /// given number of cells. The inverse of `prop_reg`.
+    pub fn set_prop_reg(
+        &mut self,
+        name: &str,
+        regions: &[(u64, u64)],
+        address_cells: u32,
+        size_cells: u32,
+    ) -> Result<(), PropError> {
+        let mut cells = Vec::new();
+        for &(address, size) in regions.iter() {
+            cells.extend(u64_to_cells(address, address_cells)?);
+            cells.extend(u64_to_cells(size, size_cells)?);
+        }
+        self.set_prop_cells(name, &cells);
+        Ok(())
+    }
+
+    /// Read a property as a list of NUL-terminated strings, such as
+    /// `compatible` or `clock-names`.
+    pub fn prop_str_list<'a>(&'a self, name: &str) -> Result<Vec<&'a str>, PropError> {
+        let raw = self.prop_raw(name).ok_or(PropError::NotFound)?;
+
+        let l = raw.len();
+        if l < 1 || raw[l - 1] != 0 {
+            return Err(PropError::Missing0);
+        }
+
+        let mut strs = Vec::new();
+        for s in raw[..(l - 1)].split(|&b| b == 0) {
+            strs.push(str::from_utf8(s)?);
+        }
+        Ok(strs)
+    }
+
+    /// Set property `name` to `value`, replacing an existing value or
+    /// appending a new property.
+    pub fn set_prop(&mut self, name: &str, value: Vec<u8>) {
+        for prop in self.props.iter_mut() {
+            if prop.0 == name {
+                prop.1 = value;
+                return;
+            }
+        }
+        self.props.push((name.to_owned(), value));
+    }
+
+    /// Remove property `name`, returning its value if it was present.
+    pub fn remove_prop(&mut self, name: &str) -> Option<Vec<u8>> {
+        let idx = self.props.iter().position(|prop| prop.0 == name)?;
+        Some(self.props.remove(idx).1)
+    }
+
+    pub fn set_prop_str(&mut self, name: &str, value: &str) {
+        let mut raw = Vec::new();
+        raw.extend_from_slice(value.as_bytes());
+        raw.push(0);
+        self.set_prop(name, raw);
+    }
+
+    pub fn set_prop_u32(&mut self, name: &str, value: u32) {
+        self.set_prop_cells(name, &[value]);
+    }
+
+    pub fn set_prop_u64(&mut self, name: &str, value: u64) {
+        self.set_prop_cells(name, &[(value >> 32) as u32, value as u32]);
+    }
+
+    pub fn set_prop_cells(&mut self, name: &str, cells: &[u32]) {
+        let mut raw = Vec::with_capacity(cells.len() * 4);
+        push_cells(&mut raw, cells);
+        self.set_prop(name, raw);
+    }
+
+    /// The phandle of this node, taken from `phandle` or `linux,phandle`.
+    pub fn phandle(&self) -> Option<u32> {
+        self.prop_u32("phandle")
+            .or_else(|_| self.prop_u32("linux,phandle"))
+            .ok()
+    }
+
+    /// Value of `#address-cells`, which applies to the children of this
+    /// node. Defaults to 2 if absent.
+    pub fn address_cells(&self) -> u32 {
+        self.prop_u32("#address-cells").unwrap_or(2)
+    }
+
+    /// Value of `#size-cells`, which applies to the children of this node.
+    /// Defaults to 1 if absent.
+    pub fn size_cells(&self) -> u32 {
+        self.prop_u32("#size-cells").unwrap_or(1)
+    }
+
+    fn find_phandle(&self, phandle: u32) -> Option<&Node> {
+        if self.phandle() == Some(phandle) {
+            return Some(self);
+        }
+
+        self.children
+            .iter()
+            .filter_map(|child| child.find_phandle(phandle))
+            .next()
+    }
+
+    fn parent_of<'a>(&'a self, node: &Node) -> Option<&'a Node> {
+        for child in self.children.iter() {
+            if ptr::eq(child, node) {
+                return Some(self);
+            }
+            if let Some(parent) = child.parent_of(node) {
+                return Some(parent);
+            }
+        }
+        None
+    }
+
+    pub fn store<W: VecWrite>(
+        &self,
+        structure: &mut W,
         strings: &mut StringTable,
     ) -> Result<(), DeviceTreeError> {
-        try!(structure.pad(4));
-        let len = structure.len();
-        try!(structure.write_be_u32(len, OF_DT_BEGIN_NODE));
+        self.store_tags(structure, strings, &mut Tags::new(None, SUPPORTED_VERSION))
+    }
+
+    fn store_tags<W: VecWrite>(
+        &self,
+        structure: &mut W,
+        strings: &mut StringTable,
+        tags: &mut Tags,
+    ) -> Result<(), DeviceTreeError> {
+        structure.pad(4)?;
+        tags.before_tag(structure)?;
+        let len = structure.offset();
+        structure.write_be_u32(len, OF_DT_BEGIN_NODE)?;
+
+        // Versions before 16 use the full path instead of the name
+        let parent_len = tags.path.len();
+        if tags.version < 16 {
+            if parent_len != 1 {
+                tags.path.push('/');
+            }
+            tags.path.push_str(&self.name);
+            structure.write_bstring0(&tags.path)?;
+        } else {
+            structure.write_bstring0(&self.name)?;
+        }
 
-        try!(structure.write_bstring0(&self.name));
         for prop in self.props.iter() {
-            try!(structure.pad(4));
-            let len = structure.len();
-            try!(structure.write_be_u32(len, OF_DT_PROP));
+            structure.pad(4)?;
+            tags.before_tag(structure)?;
+            let len = structure.offset();
+            structure.write_be_u32(len, OF_DT_PROP)?;
 
             // Write property value length
-            try!(structure.pad(4));
-            let len = structure.len();
-            try!(structure.write_be_u32(len, prop.1.len() as u32));
+            structure.pad(4)?;
+            let len = structure.offset();
+            structure.write_be_u32(len, prop.1.len() as u32)?;
 
             // Write name offset
-            try!(structure.pad(4));
-            let len = structure.len();
-            try!(structure.write_be_u32(len, strings.add_string(&prop.0)));
-
-            // Store the property value
-            structure.extend_from_slice(&prop.1);
+            structure.pad(4)?;
+            let len = structure.offset();
+            structure.write_be_u32(len, strings.add_string(&prop.0))?;
+
+            // Store the property value, which versions before 16 align to
+            // 8 bytes if it is at least that long
+            if tags.version < 16 && prop.1.len() >= 8 {
+                structure.pad(8)?;
+            }
+            structure.write_bytes(&prop.1)?;
         }
 
         // Recurse on children
         for child in self.children.iter() {
-            try!(child.store(structure, strings));
+            child.store_tags(structure, strings, tags)?;
         }
+        tags.path.truncate(parent_len);
 
-        try!(structure.pad(4));
-        let len = structure.len();
-        try!(structure.write_be_u32(len, OF_DT_END_NODE));
+        structure.pad(4)?;
+        tags.before_tag(structure)?;
+        let len = structure.offset();
+        structure.write_be_u32(len, OF_DT_END_NODE)?;
         Ok(())
     }
 }
 
+/// Write zeros up to offset `target`, if not already past it.
+fn fill<W: VecWrite>(dtb: &mut W, target: usize) -> Result<(), DeviceTreeError> {
+    while dtb.offset() < target {
+        let chunk = (target - dtb.offset()).min(ZEROS.len());
+        dtb.write_bytes(&ZEROS[..chunk])?;
+    }
+    Ok(())
+}
+
+/// Combine big-endian cells into a single number.
+pub fn cells_to_u64(cells: &[u32]) -> Result<u64, PropError> {
+    let mut val: u64 = 0;
+    for cell in cells.iter() {
+        if val >> 32 != 0 {
+            return Err(PropError::Overflow);
+        }
+        val = (val << 32) | u64::from(*cell);
+    }
+    Ok(val)
+}
+
+/// Split a number into `count` big-endian cells.
+pub fn u64_to_cells(val: u64, count: u32) -> Result<Vec<u32>, PropError> {
+    if count < 2 && val >> (32 * count) != 0 {
+        return Err(PropError::Overflow);
+    }
+
+    let mut cells = vec![0; count as usize];
+    let mut rest = val;
+    for cell in cells.iter_mut().rev() {
+        *cell = rest as u32;
+        rest = rest.checked_shr(32).unwrap_or(0);
+    }
+    Ok(cells)
+}
+
+/// Split a property value into big-endian 32 bit cells.
+fn bytes_to_cells(raw: &[u8]) -> Result<Vec<u32>, PropError> {
+    let mut cells = Vec::with_capacity(raw.len() / 4);
+    let mut pos = 0;
+    while pos < raw.len() {
+        cells.push(raw.read_be_u32(pos)?);
+        pos += 4;
+    }
+    Ok(cells)
+}
+
+/// Append cells to a property value.
+fn push_cells(raw: &mut Vec<u8>, cells: &[u32]) {
+    for cell in cells {
+        raw.extend_from_slice(&[
+            (cell >> 24) as u8,
+            (cell >> 16) as u8,
+            (cell >> 8) as u8,
+            *cell as u8,
+        ]);
+    }
+}
+
+/// Split a node name into its base name and unit address, e.g.
+/// `("serial", Some("7e201000"))` for `serial@7e201000`.
+pub fn split_name(name: &str) -> (&str, Option<&str>) {
+    match name.find('@') {
+        Some(idx) => (&name[..idx], Some(&name[(idx + 1)..])),
+        None => (name, None),
+    }
+}
+
+/// Format an address given as cells as a unit address: the cells are
+/// combined into a single number, printed in lowercase hex without leading
+/// zeros.
+pub fn format_unit_address(cells: &[u32]) -> String {
+    let mut out = String::new();
+    for cell in cells.iter() {
+        if out.is_empty() {
+            if *cell != 0 {
+                out = format!("{:x}", cell);
+            }
+        } else {
+            out.push_str(&format!("{:08x}", cell));
+        }
+    }
+
+    if out.is_empty() {
+        out.push('0');
+    }
+    out
+}
+
+/// Split a relative path into its first component and the remaining subpath.
+fn split_path(path: &str) -> (&str, &str) {
+    match path.find('/') {
+        Some(idx) => (&path[..idx], &path[(idx + 1)..]),
+        None => (path, ""),
+    }
+}
+
 impl From<str::Utf8Error> for PropError {
     fn from(_: str::Utf8Error) -> PropError {
         PropError::Utf8Error
@@ -517,9 +1207,6 @@ impl From<SliceReadError> for PropError {
 
 #[cfg(test)]
 mod test {
-    use std::fs;
-    use std::io::{Read, Write};
-
     use super::*;
 
     #[test]
@@ -529,18 +1216,49 @@ mod test {
         let original_fdt = DeviceTree::load(buf).unwrap();
 
         let dtb = original_fdt.store().unwrap();
-        let mut output = fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open("output.dtb")
-            .unwrap();
-        output.write_all(&dtb).unwrap();
-
-        let mut input = fs::File::open("output.dtb").unwrap();
-        let mut buf = Vec::new();
-        input.read_to_end(&mut buf).unwrap();
-        let generated_fdt = DeviceTree::load(buf.as_slice()).unwrap();
+        let generated_fdt = DeviceTree::load(dtb.as_slice()).unwrap();
 
         assert!(original_fdt == generated_fdt);
+        assert_eq!(&dtb[..], &buf[..]);
+    }
+
+    #[test]
+    fn store_into_buffer() {
+        let buf = include_bytes!("../examples/bcm2709-rpi-2-b.dtb");
+        let dt = DeviceTree::load(buf).unwrap();
+        let dtb = dt.store().unwrap();
+
+        assert_eq!(dt.measure().unwrap(), dtb.len());
+
+        let mut out = vec![0xff; dtb.len() + 16];
+        assert_eq!(dt.store_into(&mut out).unwrap(), dtb.len());
+        assert_eq!(&out[..dtb.len()], dtb.as_slice());
+        assert!(out[dtb.len()..].iter().all(|&b| b == 0xff));
+
+        let mut small = vec![0xff; dtb.len() - 1];
+        match dt.store_into(&mut small) {
+            Err(DeviceTreeError::BufferTooSmall(size)) => assert_eq!(size, dtb.len()),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(small.iter().all(|&b| b == 0xff));
+    }
+
+    #[test]
+    fn find_by_unit_address() {
+        let buf = include_bytes!("../examples/bcm2709-rpi-2-b.dtb");
+        let dt = DeviceTree::load(buf).unwrap();
+
+        assert_eq!(dt.find("/soc/dma").unwrap().name, "dma@7e007000");
+        assert_eq!(dt.find("/soc/@7e201000").unwrap().name, "uart@7e201000");
+        assert_eq!(dt.find("/soc/gpio/i2c0").unwrap().name, "i2c0");
+        // two uarts, so this is ambiguous
+        assert!(dt.find("/soc/uart").is_none());
+
+        let uart = dt.find("/soc/uart@7e215040").unwrap();
+        assert_eq!(uart.base_name(), "uart");
+        assert_eq!(uart.unit_address(), Some("7e215040"));
+        assert_eq!(uart.reg_unit_address(1).unwrap(), "7e215040");
+        assert_eq!(format_unit_address(&[0x1, 0x0]), "100000000");
+        assert_eq!(format_unit_address(&[0, 0]), "0");
     }
 }
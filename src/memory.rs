@@ -0,0 +1,287 @@
+//! Physical memory map
+//!
+//! RAM is described by nodes with `device_type = "memory"`, whose `reg` (or
+//! `linux,usable-memory`, if present) lists the available ranges. Parts of it
+//! are reserved, either by the memory reservation block in the header
+//! (`DeviceTree::reserved`) or by children of `/reserved-memory` with a
+//! static `reg`. What remains is free for the operating system to use.
+
+use {DeviceTree, Node, PropError};
+
+/// A contiguous range of physical memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Region {
+    pub address: u64,
+    pub size: u64,
+}
+
+/// A reserved range of memory, along with the attributes of the
+/// `/reserved-memory` node that reserved it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReservedRegion {
+    pub region: Region,
+
+    /// The region must not be mapped by the operating system (`no-map`).
+    pub no_map: bool,
+
+    /// The operating system may use the region as long as the owning driver
+    /// can reclaim it (`reusable`).
+    pub reusable: bool,
+}
+
+/// The memory layout of a system, see `DeviceTree::memory_map`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MemoryMap {
+    /// All RAM, sorted and merged.
+    pub memory: Vec<Region>,
+
+    /// RAM that is not reserved, sorted and merged.
+    pub usable: Vec<Region>,
+
+    /// Reserved ranges, sorted. Adjacent ranges are merged if their
+    /// attributes match.
+    pub reserved: Vec<ReservedRegion>,
+}
+
+impl Region {
+    pub fn new(address: u64, size: u64) -> Region {
+        Region { address, size }
+    }
+
+    /// The first address after the region.
+    pub fn end(&self) -> u64 {
+        self.address.saturating_add(self.size)
+    }
+
+    /// Whether the two regions share at least one address.
+    pub fn overlaps(&self, other: &Region) -> bool {
+        self.address < other.end() && other.address < self.end()
+    }
+}
+
+impl DeviceTree {
+    /// All RAM described by enabled memory nodes, sorted and merged.
+    ///
+    /// `linux,usable-memory` takes precedence over `reg` when present.
+    pub fn memory(&self) -> Result<Vec<Region>, PropError> {
+        let mut memory = Vec::new();
+
+        for (_, node) in self.nodes() {
+            if node.prop_str("device_type").ok() != Some("memory") || !node.is_enabled() {
+                continue;
+            }
+
+            let prop = if node.has_prop("linux,usable-memory") {
+                "linux,usable-memory"
+            } else {
+                "reg"
+            };
+            memory.extend(self.regions(node, prop)?);
+        }
+
+        merge(&mut memory);
+        Ok(memory)
+    }
+
+    /// All statically reserved memory: the entries of the memory
+    /// reservation block and enabled children of `/reserved-memory` that
+    /// have a `reg` property. Sorted by address, not merged.
+    pub fn reserved_regions(&self) -> Result<Vec<ReservedRegion>, PropError> {
+        let mut reserved: Vec<ReservedRegion> = self
+            .reserved
+            .iter()
+            .filter(|&&(_, size)| size != 0)
+            .map(|&(address, size)| ReservedRegion {
+                region: Region { address, size },
+                no_map: false,
+                reusable: false,
+            })
+            .collect();
+
+        if let Some(parent) = self.root.child("reserved-memory") {
+            for node in parent.children.iter() {
+                if !node.is_enabled() || !node.has_prop("reg") {
+                    continue;
+                }
+
+                for region in self.regions(node, "reg")? {
+                    reserved.push(ReservedRegion {
+                        region,
+                        no_map: node.has_prop("no-map"),
+                        reusable: node.has_prop("reusable"),
+                    });
+                }
+            }
+        }
+
+        reserved.sort_by_key(|r| r.region);
+        Ok(reserved)
+    }
+
+    /// Compute the memory map: all RAM, the reserved ranges and the RAM
+    /// that remains usable once every reserved range is removed.
+    pub fn memory_map(&self) -> Result<MemoryMap, PropError> {
+        let memory = self.memory()?;
+        let reserved = self.reserved_regions()?;
+
+        let mut all_reserved: Vec<Region> = reserved.iter().map(|r| r.region).collect();
+        merge(&mut all_reserved);
+
+        Ok(MemoryMap {
+            usable: subtract(&memory, &all_reserved),
+            memory,
+            reserved: merge_reserved(reserved),
+        })
+    }
+
+    /// Decode `(address, size)` pairs of `node`'s property `name` as
+    /// regions, skipping empty ones.
+    fn regions(&self, node: &Node, name: &str) -> Result<Vec<Region>, PropError> {
+        Ok(self
+            .prop_reg(node, name)?
+            .into_iter()
+            .filter(|&(_, size)| size != 0)
+            .map(|(address, size)| Region { address, size })
+            .collect())
+    }
+}
+
+/// Sort `regions` and merge overlapping or adjacent ones.
+pub fn merge(regions: &mut Vec<Region>) {
+    regions.sort();
+
+    let mut merged: Vec<Region> = Vec::with_capacity(regions.len());
+    for region in regions.drain(..) {
+        if let Some(last) = merged.last_mut() {
+            if region.address <= last.end() {
+                let end = last.end().max(region.end());
+                last.size = end - last.address;
+                continue;
+            }
+        }
+        merged.push(region);
+    }
+    *regions = merged;
+}
+
+/// Remove `remove` from `from`. Both must be sorted and merged.
+pub fn subtract(from: &[Region], remove: &[Region]) -> Vec<Region> {
+    let mut result = Vec::new();
+
+    for region in from.iter() {
+        let mut cur = region.address;
+
+        for hole in remove.iter().filter(|hole| hole.overlaps(region)) {
+            if hole.address > cur {
+                result.push(Region::new(cur, hole.address - cur));
+            }
+            cur = cur.max(hole.end());
+        }
+
+        if cur < region.end() {
+            result.push(Region::new(cur, region.end() - cur));
+        }
+    }
+
+    result
+}
+
+fn merge_reserved(reserved: Vec<ReservedRegion>) -> Vec<ReservedRegion> {
+    let mut merged: Vec<ReservedRegion> = Vec::with_capacity(reserved.len());
+
+    for r in reserved {
+        if let Some(last) = merged.last_mut() {
+            if r.region.address <= last.region.end()
+                && r.no_map == last.no_map
+                && r.reusable == last.reusable
+            {
+                let end = last.region.end().max(r.region.end());
+                last.region.size = end - last.region.address;
+                continue;
+            }
+        }
+        merged.push(r);
+    }
+    merged
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testutil::{cells, node, tree};
+
+    #[test]
+    fn memory_map() {
+        let root = node(
+            "",
+            vec![
+                ("#address-cells", cells(&[1])),
+                ("#size-cells", cells(&[1])),
+            ],
+            vec![
+                node(
+                    "memory@0",
+                    vec![
+                        ("device_type", b"memory\0".to_vec()),
+                        ("reg", cells(&[0, 0x4000_0000])),
+                    ],
+                    vec![],
+                ),
+                node(
+                    "memory@80000000",
+                    vec![
+                        ("device_type", b"memory\0".to_vec()),
+                        ("reg", cells(&[0x8000_0000, 0x4000_0000])),
+                        ("linux,usable-memory", cells(&[0x8000_0000, 0x1000_0000])),
+                    ],
+                    vec![],
+                ),
+                node(
+                    "reserved-memory",
+                    vec![
+                        ("#address-cells", cells(&[1])),
+                        ("#size-cells", cells(&[1])),
+                        ("ranges", vec![]),
+                    ],
+                    vec![
+                        node(
+                            "fb@3e000000",
+                            vec![
+                                ("reg", cells(&[0x3e00_0000, 0x0200_0000])),
+                                ("no-map", vec![]),
+                            ],
+                            vec![],
+                        ),
+                        node(
+                            "cma",
+                            vec![("size", cells(&[0x0400_0000])), ("reusable", vec![])],
+                            vec![],
+                        ),
+                    ],
+                ),
+            ],
+        );
+        let mut dt = tree(root);
+        dt.reserved = vec![(0x1000, 0x1000), (0x2000, 0x1000), (0, 0)];
+
+        let map = dt.memory_map().unwrap();
+        assert_eq!(
+            map.memory,
+            vec![
+                Region::new(0, 0x4000_0000),
+                Region::new(0x8000_0000, 0x1000_0000)
+            ]
+        );
+        assert_eq!(
+            map.usable,
+            vec![
+                Region::new(0, 0x1000),
+                Region::new(0x3000, 0x3e00_0000 - 0x3000),
+                Region::new(0x8000_0000, 0x1000_0000),
+            ]
+        );
+        assert_eq!(map.reserved.len(), 2);
+        assert_eq!(map.reserved[0].region, Region::new(0x1000, 0x2000));
+        assert!(map.reserved[1].no_map);
+    }
+}
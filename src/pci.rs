@@ -0,0 +1,489 @@
+//! PCI host bridge bindings
+//!
+//! PCI buses use three address cells. The first, `phys.hi`, encodes the
+//! address space and the bus/device/function of the device:
+//!
+//! ```text
+//! npt000ss bbbbbbbb dddddfff rrrrrrrr
+//! ```
+//!
+//! `n` marks non-relocatable addresses, `p` prefetchable memory, `t` aliased
+//! (or below 1 MiB / 64 KiB) addresses, `ss` the space (configuration, I/O,
+//! 32-bit or 64-bit memory) and `bbbbbbbb`, `ddddd`, `fff` and `rrrrrrrr`
+//! the bus, device, function and register numbers. The remaining two cells
+//! hold a 64-bit address.
+//!
+//! The `ranges` of a host bridge describe the windows through which the CPU
+//! reaches the bus. `msi-map` and `iommu-map` translate requester IDs
+//! (`bus << 8 | devfn`) to the IDs seen by MSI controllers and IOMMUs.
+
+use interrupts::{Interrupt, InterruptError};
+use util::SliceReadError;
+use {cells_to_u64, DeviceTree, Node, PropError};
+
+/// An error encountered while decoding PCI bindings.
+#[derive(Debug)]
+pub enum PciError {
+    /// A property could not be read.
+    PropError(PropError),
+
+    /// The node does not use three address cells.
+    NotPci,
+
+    /// A property ended in the middle of an entry.
+    Truncated,
+
+    /// A phandle in an ID map does not refer to any node.
+    InvalidPhandle(u32),
+
+    /// An ID map entry translates an ID beyond 32 bits.
+    IdOverflow,
+}
+
+/// The address space of a PCI address (`ss`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PciSpace {
+    Configuration,
+    Io,
+    Memory32,
+    Memory64,
+}
+
+/// A decoded three-cell PCI address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PciAddress {
+    pub space: PciSpace,
+
+    /// The address is not relocatable (`n`).
+    pub non_relocatable: bool,
+
+    /// The memory is prefetchable (`p`).
+    pub prefetchable: bool,
+
+    /// The address is aliased or below 1 MiB / 64 KiB (`t`).
+    pub aliased: bool,
+
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub register: u8,
+
+    /// The address within the space, from `phys.mid` and `phys.lo`.
+    pub address: u64,
+}
+
+/// A window of a host bridge, from one `ranges` entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PciWindow {
+    /// The start of the window on the PCI bus.
+    pub pci: PciAddress,
+
+    /// The start of the window as seen by the CPU.
+    pub cpu_address: u64,
+
+    pub size: u64,
+}
+
+/// One entry of an `msi-map` or `iommu-map`.
+#[derive(Debug, PartialEq)]
+pub struct IdMapping<'a> {
+    /// The first requester ID covered by the entry.
+    pub rid_base: u32,
+
+    /// The MSI controller or IOMMU.
+    pub target: &'a Node,
+
+    /// The ID `rid_base` is mapped to.
+    pub target_base: u32,
+
+    /// The number of IDs covered by the entry.
+    pub length: u32,
+}
+
+impl From<PropError> for PciError {
+    fn from(e: PropError) -> PciError {
+        PciError::PropError(e)
+    }
+}
+
+impl PciAddress {
+    /// Decode a PCI address from its three cells.
+    pub fn from_cells(cells: &[u32]) -> Option<PciAddress> {
+        if cells.len() != 3 {
+            return None;
+        }
+        let hi = cells[0];
+
+        let space = match (hi >> 24) & 0x3 {
+            0 => PciSpace::Configuration,
+            1 => PciSpace::Io,
+            2 => PciSpace::Memory32,
+            _ => PciSpace::Memory64,
+        };
+
+        Some(PciAddress {
+            space,
+            non_relocatable: hi & (1 << 31) != 0,
+            prefetchable: hi & (1 << 30) != 0,
+            aliased: hi & (1 << 29) != 0,
+            bus: (hi >> 16) as u8,
+            device: ((hi >> 11) & 0x1f) as u8,
+            function: ((hi >> 8) & 0x7) as u8,
+            register: hi as u8,
+            address: (u64::from(cells[1]) << 32) | u64::from(cells[2]),
+        })
+    }
+
+    /// Encode the address as three cells.
+    pub fn to_cells(&self) -> [u32; 3] {
+        let space = match self.space {
+            PciSpace::Configuration => 0,
+            PciSpace::Io => 1,
+            PciSpace::Memory32 => 2,
+            PciSpace::Memory64 => 3,
+        };
+
+        let hi = (u32::from(self.non_relocatable) << 31)
+            | (u32::from(self.prefetchable) << 30)
+            | (u32::from(self.aliased) << 29)
+            | (space << 24)
+            | (u32::from(self.bus) << 16)
+            | (u32::from(self.device & 0x1f) << 11)
+            | (u32::from(self.function & 0x7) << 8)
+            | u32::from(self.register);
+
+        [hi, (self.address >> 32) as u32, self.address as u32]
+    }
+
+    /// The requester ID, `bus << 8 | device << 3 | function`.
+    pub fn rid(&self) -> u32 {
+        (u32::from(self.bus) << 8) | (u32::from(self.device) << 3) | u32::from(self.function)
+    }
+}
+
+impl PciWindow {
+    /// Translate an address on the PCI bus to a CPU address, if it falls
+    /// within the window.
+    pub fn to_cpu(&self, pci_address: u64) -> Option<u64> {
+        let offset = pci_address.checked_sub(self.pci.address)?;
+        if offset >= self.size {
+            return None;
+        }
+        Some(self.cpu_address + offset)
+    }
+}
+
+impl Node {
+    /// Whether this node is on a PCI bus or is a PCI bridge, i.e. uses
+    /// `device_type = "pci"`.
+    pub fn is_pci_bus(&self) -> bool {
+        self.prop_str("device_type").ok() == Some("pci")
+    }
+
+    /// The bus numbers behind a host bridge, from `bus-range`.
+    ///
+    /// Defaults to `0..=255` if the property is missing.
+    pub fn bus_range(&self) -> Result<(u32, u32), PropError> {
+        match self.prop_cells("bus-range") {
+            Ok(ref cells) if cells.len() == 2 => Ok((cells[0], cells[1])),
+            Ok(_) => Err(PropError::SliceReadError(
+                SliceReadError::UnexpectedEndOfInput,
+            )),
+            Err(PropError::NotFound) => Ok((0, 0xff)),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Decode the `reg` of a device on a PCI bus as PCI addresses and
+    /// sizes. `size_cells` is the `#size-cells` of the bus, usually `2`.
+    pub fn pci_reg(&self, size_cells: u32) -> Result<Vec<(PciAddress, u64)>, PciError> {
+        let cells = self.prop_cells("reg")?;
+        let entry_size = 3 + size_cells as usize;
+        if cells.len() % entry_size != 0 {
+            return Err(PciError::Truncated);
+        }
+
+        let mut regs = Vec::new();
+        for entry in cells.chunks(entry_size) {
+            let address = PciAddress::from_cells(&entry[..3]).ok_or(PciError::Truncated)?;
+            regs.push((address, cells_to_u64(&entry[3..])?));
+        }
+        Ok(regs)
+    }
+}
+
+impl DeviceTree {
+    /// The windows of host bridge `bridge`, decoded from its `ranges`.
+    pub fn pci_windows(&self, bridge: &Node) -> Result<Vec<PciWindow>, PciError> {
+        if bridge.address_cells() != 3 {
+            return Err(PciError::NotPci);
+        }
+
+        let parent_cells = match self.parent_of(bridge) {
+            Some(parent) => parent.address_cells() as usize,
+            None => 2,
+        };
+        let size_cells = bridge.size_cells() as usize;
+        let entry_size = 3 + parent_cells + size_cells;
+
+        let cells = match bridge.prop_cells("ranges") {
+            Ok(cells) => cells,
+            Err(PropError::NotFound) => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        if cells.len() % entry_size != 0 {
+            return Err(PciError::Truncated);
+        }
+
+        let mut windows = Vec::new();
+        for entry in cells.chunks(entry_size) {
+            let (pci, rest) = entry.split_at(3);
+            let (cpu, size) = rest.split_at(parent_cells);
+
+            windows.push(PciWindow {
+                pci: PciAddress::from_cells(pci).ok_or(PciError::Truncated)?,
+                cpu_address: cells_to_u64(cpu)?,
+                size: cells_to_u64(size)?,
+            });
+        }
+        Ok(windows)
+    }
+
+    /// The entries of `msi-map` of host bridge `bridge`.
+    pub fn msi_map<'a>(&'a self, bridge: &Node) -> Result<Vec<IdMapping<'a>>, PciError> {
+        self.id_map(bridge, "msi-map")
+    }
+
+    /// The entries of `iommu-map` of host bridge `bridge`.
+    pub fn iommu_map<'a>(&'a self, bridge: &Node) -> Result<Vec<IdMapping<'a>>, PciError> {
+        self.id_map(bridge, "iommu-map")
+    }
+
+    /// Translate requester ID `rid` through `<stem>-map` and
+    /// `<stem>-map-mask` of `bridge`, where `stem` is `"msi"` or `"iommu"`.
+    ///
+    /// Returns `None` if the bridge has no such map or none of its entries
+    /// covers the ID, in which case the ID is used unchanged, like in the
+    /// kernel's `of_map_id`.
+    pub fn map_rid<'a>(
+        &'a self,
+        bridge: &Node,
+        stem: &str,
+        rid: u32,
+    ) -> Result<Option<(&'a Node, u32)>, PciError> {
+        let map_name = format!("{}-map", stem);
+        if !bridge.has_prop(&map_name) {
+            return Ok(None);
+        }
+
+        let mask = match bridge.prop_u32(&format!("{}-map-mask", stem)) {
+            Ok(mask) => mask,
+            Err(PropError::NotFound) => 0xffff_ffff,
+            Err(e) => return Err(e.into()),
+        };
+        let masked = rid & mask;
+
+        for entry in self.id_map(bridge, &map_name)? {
+            let offset = match masked.checked_sub(entry.rid_base) {
+                Some(offset) if offset < entry.length => offset,
+                _ => continue,
+            };
+            let id = entry
+                .target_base
+                .checked_add(offset)
+                .ok_or(PciError::IdOverflow)?;
+            return Ok(Some((entry.target, id)));
+        }
+        Ok(None)
+    }
+
+    /// Route legacy interrupt `pin` (1 for INTA through 4 for INTD) of the
+    /// device at `bus`/`device`/`function` below `bridge` through the
+    /// bridge's `interrupt-map`.
+    pub fn pci_interrupt<'a>(
+        &'a self,
+        bridge: &'a Node,
+        bus: u8,
+        device: u8,
+        function: u8,
+        pin: u32,
+    ) -> Result<Interrupt<'a>, InterruptError> {
+        let address = PciAddress {
+            space: PciSpace::Configuration,
+            non_relocatable: false,
+            prefetchable: false,
+            aliased: false,
+            bus,
+            device,
+            function,
+            register: 0,
+            address: 0,
+        };
+        self.map_interrupt(bridge, Some(&address.to_cells()), vec![pin])
+    }
+
+    fn id_map<'a>(&'a self, bridge: &Node, name: &str) -> Result<Vec<IdMapping<'a>>, PciError> {
+        let cells = match bridge.prop_cells(name) {
+            Ok(cells) => cells,
+            Err(PropError::NotFound) => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        if cells.len() % 4 != 0 {
+            return Err(PciError::Truncated);
+        }
+
+        let mut map = Vec::new();
+        for entry in cells.chunks(4) {
+            let target = self
+                .find_phandle(entry[1])
+                .ok_or(PciError::InvalidPhandle(entry[1]))?;
+            map.push(IdMapping {
+                rid_base: entry[0],
+                target,
+                target_base: entry[2],
+                length: entry[3],
+            });
+        }
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testutil::{cells, node, tree};
+
+    #[test]
+    fn address_cells() {
+        let cells = [0xc300_0000, 0x1, 0x8000_0000];
+        let address = PciAddress::from_cells(&cells).unwrap();
+        assert_eq!(address.space, PciSpace::Memory64);
+        assert!(address.non_relocatable);
+        assert!(address.prefetchable);
+        assert_eq!(address.address, 0x1_8000_0000);
+        assert_eq!(address.to_cells(), cells);
+
+        let config = PciAddress::from_cells(&[0x0001_0a10, 0, 0]).unwrap();
+        assert_eq!(config.space, PciSpace::Configuration);
+        assert_eq!((config.bus, config.device, config.function), (1, 1, 2));
+        assert_eq!(config.register, 0x10);
+        assert_eq!(config.rid(), 0x10a);
+        assert!(PciAddress::from_cells(&[0, 0]).is_none());
+    }
+
+    #[test]
+    fn host_bridge() {
+        let root = node(
+            "",
+            vec![
+                ("#address-cells", cells(&[2])),
+                ("#size-cells", cells(&[2])),
+            ],
+            vec![
+                node(
+                    "gic",
+                    vec![
+                        ("interrupt-controller", vec![]),
+                        ("#interrupt-cells", cells(&[1])),
+                        ("#address-cells", cells(&[0])),
+                        ("phandle", cells(&[1])),
+                    ],
+                    vec![],
+                ),
+                node("its", vec![("phandle", cells(&[2]))], vec![]),
+                node(
+                    "pcie@40000000",
+                    vec![
+                        ("device_type", b"pci\0".to_vec()),
+                        ("#address-cells", cells(&[3])),
+                        ("#size-cells", cells(&[2])),
+                        ("#interrupt-cells", cells(&[1])),
+                        ("bus-range", cells(&[0, 0x7f])),
+                        (
+                            "ranges",
+                            cells(&[
+                                0x0100_0000,
+                                0,
+                                0,
+                                0,
+                                0x3eff_0000,
+                                0,
+                                0x1_0000, //
+                                0x0200_0000,
+                                0,
+                                0x4000_0000,
+                                0,
+                                0x4000_0000,
+                                0,
+                                0x4000_0000, //
+                                0x4300_0000,
+                                0x80,
+                                0,
+                                0x80,
+                                0,
+                                0x80,
+                                0,
+                            ]),
+                        ),
+                        ("msi-map", cells(&[0, 2, 0x1_0000, 0x100])),
+                        ("msi-map-mask", cells(&[0xff])),
+                        ("interrupt-map-mask", cells(&[0x1800, 0, 0, 7])),
+                        (
+                            "interrupt-map",
+                            cells(&[0, 0, 0, 1, 1, 32, 0x800, 0, 0, 1, 1, 33]),
+                        ),
+                    ],
+                    vec![node(
+                        "ethernet@1,0",
+                        vec![("reg", cells(&[0x0000_0800, 0, 0, 0, 0]))],
+                        vec![],
+                    )],
+                ),
+            ],
+        );
+        let dt = tree(root);
+        let bridge = dt.find("/pcie@40000000").unwrap();
+
+        assert!(bridge.is_pci_bus());
+        assert_eq!(bridge.bus_range().unwrap(), (0, 0x7f));
+
+        let windows = dt.pci_windows(bridge).unwrap();
+        assert_eq!(windows.len(), 3);
+        assert_eq!(windows[0].pci.space, PciSpace::Io);
+        assert_eq!(windows[0].cpu_address, 0x3eff_0000);
+        assert_eq!(windows[1].to_cpu(0x4000_1000), Some(0x4000_1000));
+        assert_eq!(windows[1].to_cpu(0x8000_0000), None);
+        assert!(windows[2].pci.prefetchable);
+        assert_eq!(windows[2].size, 0x80_0000_0000);
+
+        let eth = bridge.child("ethernet").unwrap();
+        let reg = eth.pci_reg(2).unwrap();
+        assert_eq!((reg[0].0.device, reg[0].1), (1, 0));
+
+        let (its, id) = dt.map_rid(bridge, "msi", 0x108).unwrap().unwrap();
+        assert_eq!(its.name, "its");
+        assert_eq!(id, 0x1_0008);
+        assert!(dt.map_rid(bridge, "iommu", 0x108).unwrap().is_none());
+        assert!(dt.msi_map(dt.find("/its").unwrap()).unwrap().is_empty());
+
+        let irq = dt.pci_interrupt(bridge, 0, 1, 0, 1).unwrap();
+        assert_eq!(irq.controller.name, "gic");
+        assert_eq!(irq.specifier, vec![33]);
+
+        assert!(matches!(
+            dt.pci_windows(dt.find("/gic").unwrap()),
+            Err(PciError::NotPci)
+        ));
+
+        let mut dt = dt;
+        dt.find_mut("/pcie@40000000")
+            .unwrap()
+            .set_prop_cells("msi-map", &[0x10, 2, 0xffff_fff0, 0x20]);
+        let bridge = dt.find("/pcie@40000000").unwrap();
+        assert!(dt.map_rid(bridge, "msi", 0x8).unwrap().is_none());
+        assert!(matches!(
+            dt.map_rid(bridge, "msi", 0x28),
+            Err(PciError::IdOverflow)
+        ));
+    }
+}
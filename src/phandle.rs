@@ -0,0 +1,353 @@
+//! Phandle lists with argument cells
+//!
+//! Most bindings reference other nodes through list properties such as
+//! `clocks`, `gpios`, `dmas` or `resets`. Every entry in such a list is a
+//! phandle followed by a number of argument cells. The number of arguments is
+//! taken from a `#*-cells` property (`#clock-cells`, `#gpio-cells`, ...) on
+//! the referenced node, which is why lists can only be decoded with access to
+//! the whole tree. This mirrors the kernel's `of_parse_phandle_with_args`.
+//!
+//! Nexus nodes can remap specifiers through `*-map`, `*-map-mask` and
+//! `*-map-pass-thru` properties (e.g. `gpio-map` on a connector), see
+//! `PhandleArgs::map`.
+
+use {DeviceTree, Node, PropError};
+
+/// An error encountered while decoding a phandle list.
+#[derive(Debug)]
+pub enum PhandleError {
+    /// A property could not be read.
+    PropError(PropError),
+
+    /// A phandle does not refer to any node in the tree.
+    InvalidPhandle(u32),
+
+    /// The referenced node lacks the `#*-cells` property.
+    MissingCells,
+
+    /// The entry is an empty placeholder (a phandle of `0`).
+    Empty,
+
+    /// A list or map ended in the middle of an entry.
+    Truncated,
+
+    /// There is no entry with the requested index or name.
+    NotFound,
+
+    /// None of the entries in a `*-map` matched the specifier.
+    NoMapEntry,
+
+    /// The `*-map` properties lead around in a cycle.
+    MapCycle,
+}
+
+/// A single entry of a phandle list: the referenced node and its argument
+/// cells.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PhandleArgs<'a> {
+    /// The node the phandle refers to.
+    pub node: &'a Node,
+
+    /// The argument cells following the phandle.
+    pub args: Vec<u32>,
+}
+
+/// Iterator over the entries of a phandle list, see `Node::phandle_args`.
+pub struct PhandleArgsIter<'a> {
+    tree: &'a DeviceTree,
+    cells: Vec<u32>,
+    cells_name: String,
+    pos: usize,
+}
+
+impl From<PropError> for PhandleError {
+    fn from(e: PropError) -> PhandleError {
+        PhandleError::PropError(e)
+    }
+}
+
+impl<'a> Iterator for PhandleArgsIter<'a> {
+    type Item = Result<PhandleArgs<'a>, PhandleError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.cells.len() {
+            return None;
+        }
+
+        let phandle = self.cells[self.pos];
+        self.pos += 1;
+
+        // placeholder entries carry no arguments
+        if phandle == 0 {
+            return Some(Err(PhandleError::Empty));
+        }
+
+        let result = self.parse_entry(phandle);
+        if result.is_err() {
+            // without the argument count we cannot find the next entry
+            self.pos = self.cells.len();
+        }
+        Some(result)
+    }
+}
+
+impl<'a> PhandleArgsIter<'a> {
+    fn parse_entry(&mut self, phandle: u32) -> Result<PhandleArgs<'a>, PhandleError> {
+        let node = self
+            .tree
+            .find_phandle(phandle)
+            .ok_or(PhandleError::InvalidPhandle(phandle))?;
+        let count = node
+            .prop_u32(&self.cells_name)
+            .map_err(|_| PhandleError::MissingCells)? as usize;
+
+        if self.pos + count > self.cells.len() {
+            return Err(PhandleError::Truncated);
+        }
+
+        let args = self.cells[self.pos..(self.pos + count)].to_vec();
+        self.pos += count;
+
+        Ok(PhandleArgs { node, args })
+    }
+}
+
+impl<'a> PhandleArgs<'a> {
+    /// Follow nexus `<stem>-map` properties until a node without a map is
+    /// reached.
+    ///
+    /// `stem` is the binding name, e.g. `"gpio"` for `gpio-map`,
+    /// `gpio-map-mask`, `gpio-map-pass-thru` and `#gpio-cells`. Entries are
+    /// returned unchanged if the referenced node has no map. Fails with
+    /// `MapCycle` if the maps lead back to a nexus node.
+    pub fn map(self, tree: &'a DeviceTree, stem: &str) -> Result<PhandleArgs<'a>, PhandleError> {
+        let cells_name = format!("#{}-cells", stem);
+        let map_name = format!("{}-map", stem);
+        let mask_name = format!("{}-map-mask", stem);
+        let pass_name = format!("{}-map-pass-thru", stem);
+
+        let PhandleArgs { mut node, mut args } = self;
+
+        // without a cycle, every node is visited at most once
+        for _ in 0..tree.nodes().count() {
+            let map = match node.prop_cells(&map_name) {
+                Ok(map) => map,
+                Err(PropError::NotFound) => return Ok(PhandleArgs { node, args }),
+                Err(e) => return Err(e.into()),
+            };
+            let mask = node.prop_cells(&mask_name).unwrap_or_default();
+            let pass = node.prop_cells(&pass_name).unwrap_or_default();
+
+            let child_size = args.len();
+            let mut pos = 0;
+
+            loop {
+                if pos == map.len() {
+                    return Err(PhandleError::NoMapEntry);
+                }
+                if pos + child_size + 1 > map.len() {
+                    return Err(PhandleError::Truncated);
+                }
+
+                let matches = (0..child_size).all(|i| {
+                    (args[i] ^ map[pos + i]) & mask.get(i).cloned().unwrap_or(0xffff_ffff) == 0
+                });
+                pos += child_size;
+
+                let phandle = map[pos];
+                pos += 1;
+
+                let parent = tree
+                    .find_phandle(phandle)
+                    .ok_or(PhandleError::InvalidPhandle(phandle))?;
+                let parent_size = parent
+                    .prop_u32(&cells_name)
+                    .map_err(|_| PhandleError::MissingCells)?
+                    as usize;
+
+                if pos + parent_size > map.len() {
+                    return Err(PhandleError::Truncated);
+                }
+
+                if matches {
+                    let mut parent_args = map[pos..(pos + parent_size)].to_vec();
+                    for (i, arg) in parent_args.iter_mut().enumerate().take(child_size) {
+                        let pass = pass.get(i).cloned().unwrap_or(0);
+                        *arg = (*arg & !pass) | (args[i] & pass);
+                    }
+
+                    node = parent;
+                    args = parent_args;
+                    break;
+                }
+
+                pos += parent_size;
+            }
+        }
+        Err(PhandleError::MapCycle)
+    }
+}
+
+impl Node {
+    /// Iterate over the entries of the phandle list `list_name`, using the
+    /// `cells_name` property of each referenced node as argument count.
+    ///
+    /// For example, `node.phandle_args(&tree, "clocks", "#clock-cells")`.
+    pub fn phandle_args<'a>(
+        &self,
+        tree: &'a DeviceTree,
+        list_name: &str,
+        cells_name: &str,
+    ) -> Result<PhandleArgsIter<'a>, PropError> {
+        Ok(PhandleArgsIter {
+            tree,
+            cells: self.prop_cells(list_name)?,
+            cells_name: cells_name.to_owned(),
+            pos: 0,
+        })
+    }
+
+    /// Return the entry at `index` of the phandle list `list_name`.
+    pub fn phandle_args_at<'a>(
+        &self,
+        tree: &'a DeviceTree,
+        list_name: &str,
+        cells_name: &str,
+        index: usize,
+    ) -> Result<PhandleArgs<'a>, PhandleError> {
+        self.phandle_args(tree, list_name, cells_name)?
+            .nth(index)
+            .unwrap_or(Err(PhandleError::NotFound))
+    }
+
+    /// Return the entry of the phandle list `list_name` whose position
+    /// matches that of `name` in the string list `names_name`.
+    ///
+    /// For example, `node.phandle_args_by_name(&tree, "clocks",
+    /// "#clock-cells", "clock-names", "apb_pclk")`.
+    pub fn phandle_args_by_name<'a>(
+        &self,
+        tree: &'a DeviceTree,
+        list_name: &str,
+        cells_name: &str,
+        names_name: &str,
+        name: &str,
+    ) -> Result<PhandleArgs<'a>, PhandleError> {
+        let index = self
+            .prop_str_list(names_name)?
+            .iter()
+            .position(|n| *n == name)
+            .ok_or(PhandleError::NotFound)?;
+
+        self.phandle_args_at(tree, list_name, cells_name, index)
+    }
+
+    /// Return the entry at `index` of the phandle list `list_name`, after
+    /// remapping it through any `<stem>-map` nexus nodes.
+    ///
+    /// This is the equivalent of the kernel's
+    /// `of_parse_phandle_with_args_map`, e.g.
+    /// `node.phandle_args_map(&tree, "gpios", "gpio", 0)`.
+    pub fn phandle_args_map<'a>(
+        &self,
+        tree: &'a DeviceTree,
+        list_name: &str,
+        stem: &str,
+        index: usize,
+    ) -> Result<PhandleArgs<'a>, PhandleError> {
+        let cells_name = format!("#{}-cells", stem);
+        self.phandle_args_at(tree, list_name, &cells_name, index)?
+            .map(tree, stem)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testutil::{cells, node, rpi, tree};
+
+    #[test]
+    fn clocks_and_dmas() {
+        let dt = rpi();
+
+        let uart = dt.find("/soc/uart@7e201000").unwrap();
+        let clocks: Vec<_> = uart
+            .phandle_args(&dt, "clocks", "#clock-cells")
+            .unwrap()
+            .map(|e| e.unwrap())
+            .collect();
+        assert_eq!(clocks.len(), 2);
+        assert_eq!(clocks[0].node.name, "clock@2");
+        assert!(clocks[0].args.is_empty());
+
+        let apb = uart
+            .phandle_args_by_name(&dt, "clocks", "#clock-cells", "clock-names", "apb_pclk")
+            .unwrap();
+        assert_eq!(apb.node.name, "clock@3");
+
+        let sdhost = dt.find("/soc/sdhost@7e202000").unwrap();
+        let dma = sdhost
+            .phandle_args_at(&dt, "dmas", "#dma-cells", 0)
+            .unwrap();
+        assert_eq!(dma.node.name, "dma@7e007000");
+        assert_eq!(dma.args, vec![0xd]);
+    }
+
+    #[test]
+    fn gpio_map() {
+        let root = node(
+            "",
+            vec![],
+            vec![
+                node(
+                    "gpio",
+                    vec![("phandle", cells(&[1])), ("#gpio-cells", cells(&[2]))],
+                    vec![],
+                ),
+                node(
+                    "connector",
+                    vec![
+                        ("phandle", cells(&[2])),
+                        ("#gpio-cells", cells(&[2])),
+                        ("gpio-map", cells(&[0, 0, 1, 12, 0, 1, 0, 1, 13, 0])),
+                        ("gpio-map-mask", cells(&[0xf, 0])),
+                        ("gpio-map-pass-thru", cells(&[0, 0x1])),
+                    ],
+                    vec![],
+                ),
+                node("led", vec![("gpios", cells(&[0, 2, 1, 1]))], vec![]),
+                node(
+                    "loop",
+                    vec![
+                        ("phandle", cells(&[3])),
+                        ("#gpio-cells", cells(&[1])),
+                        ("gpio-map", cells(&[0, 3, 0])),
+                        ("gpio-map-mask", cells(&[0])),
+                    ],
+                    vec![],
+                ),
+            ],
+        );
+        let dt = tree(root);
+
+        let led = dt.find("/led").unwrap();
+        let mut iter = led.phandle_args(&dt, "gpios", "#gpio-cells").unwrap();
+        match iter.next() {
+            Some(Err(PhandleError::Empty)) => (),
+            other => panic!("expected empty entry, got {:?}", other),
+        }
+
+        let gpio = led.phandle_args_map(&dt, "gpios", "gpio", 1).unwrap();
+        assert_eq!(gpio.node.name, "gpio");
+        assert_eq!(gpio.args, vec![13, 1]);
+
+        let entry = PhandleArgs {
+            node: dt.find("/loop").unwrap(),
+            args: vec![7],
+        };
+        assert!(matches!(
+            entry.map(&dt, "gpio"),
+            Err(PhandleError::MapCycle)
+        ));
+    }
+}
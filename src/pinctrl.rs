@@ -0,0 +1,131 @@
+//! Pin control states
+//!
+//! A device selects its pin configuration through numbered states:
+//! `pinctrl-0`, `pinctrl-1`, ... each list phandles of pin configuration
+//! nodes, usually children of the pin controller, and `pinctrl-names` names
+//! the states, e.g. `"default"` and `"sleep"`. A state may be empty.
+
+use phandle::PhandleError;
+use {DeviceTree, Node};
+
+/// A pin control state of a device.
+#[derive(Debug, PartialEq)]
+pub struct PinctrlState<'a> {
+    /// The state number `N` of `pinctrl-N`.
+    pub id: u32,
+
+    /// The name from `pinctrl-names`, if any.
+    pub name: Option<&'a str>,
+
+    /// The pin configuration nodes to apply.
+    pub configs: Vec<&'a Node>,
+}
+
+impl Node {
+    /// All pin control states of this node, in order of their number.
+    ///
+    /// States are read from `pinctrl-0` upwards until the first missing one,
+    /// as the kernel does.
+    pub fn pinctrl_states<'a>(
+        &'a self,
+        tree: &'a DeviceTree,
+    ) -> Result<Vec<PinctrlState<'a>>, PhandleError> {
+        let names = self.prop_str_list("pinctrl-names").unwrap_or_default();
+        let mut states = Vec::new();
+
+        for id in 0.. {
+            let phandles = match self.prop_cells(&format!("pinctrl-{}", id)) {
+                Ok(phandles) => phandles,
+                Err(_) => break,
+            };
+
+            let mut configs = Vec::with_capacity(phandles.len());
+            for phandle in phandles {
+                let config = tree
+                    .find_phandle(phandle)
+                    .ok_or(PhandleError::InvalidPhandle(phandle))?;
+                configs.push(config);
+            }
+
+            states.push(PinctrlState {
+                id,
+                name: names.get(id as usize).cloned(),
+                configs,
+            });
+        }
+        Ok(states)
+    }
+
+    /// The pin control state named `name`, e.g. `"default"`.
+    pub fn pinctrl_state<'a>(
+        &'a self,
+        tree: &'a DeviceTree,
+        name: &str,
+    ) -> Result<PinctrlState<'a>, PhandleError> {
+        self.pinctrl_states(tree)?
+            .into_iter()
+            .find(|s| s.name == Some(name))
+            .ok_or(PhandleError::NotFound)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testutil::{cells, node, rpi, tree};
+
+    #[test]
+    fn rpi_pinctrl() {
+        let dt = rpi();
+
+        let sdhost = dt.find("/soc/sdhost@7e202000").unwrap();
+        let state = sdhost.pinctrl_state(&dt, "default").unwrap();
+        assert_eq!(state.id, 0);
+        assert_eq!(state.configs.len(), 1);
+        assert_eq!(state.configs[0].name, "sdhost_pins");
+        assert_eq!(
+            state.configs[0].prop_cells("brcm,pins").unwrap(),
+            vec![48, 49, 50, 51, 52, 53]
+        );
+    }
+
+    #[test]
+    fn states() {
+        let root = node(
+            "",
+            vec![],
+            vec![
+                node(
+                    "pinctrl",
+                    vec![],
+                    vec![
+                        node("uart_tx", vec![("phandle", cells(&[1]))], vec![]),
+                        node("uart_rx", vec![("phandle", cells(&[2]))], vec![]),
+                    ],
+                ),
+                node(
+                    "uart",
+                    vec![
+                        ("pinctrl-names", b"default\0sleep\0".to_vec()),
+                        ("pinctrl-0", cells(&[1, 2])),
+                        ("pinctrl-1", vec![]),
+                        ("pinctrl-3", cells(&[1])),
+                    ],
+                    vec![],
+                ),
+            ],
+        );
+        let dt = tree(root);
+        let uart = dt.find("/uart").unwrap();
+
+        let states = uart.pinctrl_states(&dt).unwrap();
+        assert_eq!(states.len(), 2);
+        assert_eq!(states[0].configs[1].name, "uart_rx");
+        assert_eq!(states[1].name, Some("sleep"));
+        assert!(states[1].configs.is_empty());
+        assert!(matches!(
+            uart.pinctrl_state(&dt, "idle"),
+            Err(PhandleError::NotFound)
+        ));
+    }
+}
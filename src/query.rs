@@ -0,0 +1,295 @@
+//! Selecting nodes with path queries
+//!
+//! A selector is an absolute path whose components may contain wildcards and
+//! predicates:
+//!
+//! * `*` matches any sequence of characters in a node name and `?` matches a
+//!   single character, e.g. `/soc/*@7e2*`. Names are matched including their
+//!   unit address.
+//! * `**` matches any number of levels, including none, e.g. `/**/i2c*`.
+//! * `[prop]` requires the node to have property `prop`.
+//! * `[prop="value"]` requires `prop` to be the string `value`.
+//! * `[prop~="value"]` requires `value` to be one of the strings in the
+//!   string list `prop`, e.g. `[compatible~="arm,pl011"]`.
+//!
+//! For example, `/soc/**/*[status="okay"]` selects all enabled nodes below
+//! `/soc`.
+
+use {DeviceTree, Node};
+
+/// An error in the syntax of a selector.
+#[derive(Debug, PartialEq)]
+pub enum QueryError {
+    /// Selectors must start with a `/`.
+    NotAbsolute,
+
+    /// Unexpected character or end of input at the given position.
+    Syntax(usize),
+}
+
+/// A parsed selector, see the module documentation for the syntax.
+#[derive(Debug, PartialEq)]
+pub struct Selector {
+    steps: Vec<Step>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Step {
+    AnyDepth,
+    Child(String, Vec<Predicate>),
+}
+
+#[derive(Debug, PartialEq)]
+enum Predicate {
+    Has(String),
+    Equals(String, String),
+    Contains(String, String),
+}
+
+impl Selector {
+    pub fn parse(selector: &str) -> Result<Selector, QueryError> {
+        if !selector.starts_with('/') {
+            return Err(QueryError::NotAbsolute);
+        }
+
+        let mut parser = Parser {
+            input: selector.as_bytes(),
+            pos: 1,
+        };
+        let mut steps = Vec::new();
+
+        while parser.pos < parser.input.len() {
+            steps.push(parser.step()?);
+
+            match parser.peek() {
+                None => break,
+                Some(b'/') => parser.pos += 1,
+                Some(_) => return Err(QueryError::Syntax(parser.pos)),
+            }
+        }
+
+        Ok(Selector { steps })
+    }
+
+    /// Find all nodes of `tree` matching this selector, in document order.
+    pub fn select<'a>(&self, tree: &'a DeviceTree) -> Vec<(String, &'a Node)> {
+        let mut matched = Vec::new();
+        select_from(&self.steps, &tree.root, &mut matched);
+
+        // `**` can reach a node more than once, so report matches by walking
+        // the tree once and looking nodes up by address
+        let mut matched: Vec<*const Node> = matched.into_iter().map(|m| m as *const Node).collect();
+        matched.sort();
+        matched.dedup();
+
+        tree.paths()
+            .filter(|&(_, node)| matched.binary_search(&(node as *const Node)).is_ok())
+            .collect()
+    }
+}
+
+impl DeviceTree {
+    /// Find all nodes matching `selector`, along with their paths.
+    ///
+    /// See the `query` module for the selector syntax.
+    pub fn select(&self, selector: &str) -> Result<Vec<(String, &Node)>, QueryError> {
+        Ok(Selector::parse(selector)?.select(self))
+    }
+}
+
+fn select_from<'a>(steps: &[Step], node: &'a Node, matched: &mut Vec<&'a Node>) {
+    let (step, rest) = match steps.split_first() {
+        Some(split) => split,
+        None => {
+            matched.push(node);
+            return;
+        }
+    };
+
+    match *step {
+        Step::AnyDepth => {
+            select_from(rest, node, matched);
+            for child in node.children.iter() {
+                select_from(steps, child, matched);
+            }
+        }
+        Step::Child(ref pattern, ref predicates) => {
+            for child in node.children.iter() {
+                if glob(pattern.as_bytes(), child.name.as_bytes())
+                    && predicates.iter().all(|p| p.matches(child))
+                {
+                    select_from(rest, child, matched);
+                }
+            }
+        }
+    }
+}
+
+impl Predicate {
+    fn matches(&self, node: &Node) -> bool {
+        match *self {
+            Predicate::Has(ref prop) => node.has_prop(prop),
+            Predicate::Equals(ref prop, ref value) => node.prop_str(prop).ok() == Some(value),
+            Predicate::Contains(ref prop, ref value) => node
+                .prop_str_list(prop)
+                .map(|list| list.contains(&value.as_str()))
+                .unwrap_or(false),
+        }
+    }
+}
+
+/// Match `name` against a pattern with `*` and `?` wildcards.
+fn glob(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((&b'*', rest)) => (0..=name.len()).any(|skip| glob(rest, &name[skip..])),
+        Some((&b'?', rest)) => !name.is_empty() && glob(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && glob(rest, &name[1..]),
+    }
+}
+
+struct Parser<'s> {
+    input: &'s [u8],
+    pos: usize,
+}
+
+impl<'s> Parser<'s> {
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).cloned()
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), QueryError> {
+        if self.peek() != Some(c) {
+            return Err(QueryError::Syntax(self.pos));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    /// Read characters up to (not including) any of `stop`.
+    fn until(&mut self, stop: &[u8]) -> Result<String, QueryError> {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if stop.contains(&c) {
+                break;
+            }
+            self.pos += 1;
+        }
+
+        if self.pos == start {
+            return Err(QueryError::Syntax(self.pos));
+        }
+        Ok(String::from_utf8_lossy(&self.input[start..self.pos]).into_owned())
+    }
+
+    fn step(&mut self) -> Result<Step, QueryError> {
+        let pattern = self.until(b"/[")?;
+        let mut predicates = Vec::new();
+
+        while self.peek() == Some(b'[') {
+            self.pos += 1;
+            predicates.push(self.predicate()?);
+        }
+
+        if pattern == "**" {
+            if !predicates.is_empty() {
+                return Err(QueryError::Syntax(self.pos));
+            }
+            return Ok(Step::AnyDepth);
+        }
+        Ok(Step::Child(pattern, predicates))
+    }
+
+    fn predicate(&mut self) -> Result<Predicate, QueryError> {
+        let prop = self.until(b"=~]")?;
+
+        match self.peek() {
+            Some(b']') => {
+                self.pos += 1;
+                Ok(Predicate::Has(prop))
+            }
+            Some(b'=') => {
+                self.pos += 1;
+                let value = self.quoted()?;
+                self.expect(b']')?;
+                Ok(Predicate::Equals(prop, value))
+            }
+            Some(b'~') => {
+                self.pos += 1;
+                self.expect(b'=')?;
+                let value = self.quoted()?;
+                self.expect(b']')?;
+                Ok(Predicate::Contains(prop, value))
+            }
+            _ => Err(QueryError::Syntax(self.pos)),
+        }
+    }
+
+    fn quoted(&mut self) -> Result<String, QueryError> {
+        self.expect(b'"')?;
+
+        let mut value = Vec::new();
+        loop {
+            match self.peek() {
+                None => return Err(QueryError::Syntax(self.pos)),
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    self.pos += 1;
+                    let c = self.peek().ok_or(QueryError::Syntax(self.pos))?;
+                    value.push(c);
+                }
+                Some(c) => value.push(c),
+            }
+            self.pos += 1;
+        }
+        self.pos += 1;
+
+        Ok(String::from_utf8_lossy(&value).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testutil::rpi;
+
+    fn paths(dt: &DeviceTree, selector: &str) -> Vec<String> {
+        dt.select(selector)
+            .unwrap()
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect()
+    }
+
+    #[test]
+    fn select() {
+        let dt = rpi();
+
+        assert_eq!(paths(&dt, "/"), vec!["/"]);
+        assert_eq!(
+            paths(&dt, "/soc/i2?@7e20*"),
+            vec!["/soc/i2s@7e203000", "/soc/i2c@7e205000"]
+        );
+        assert_eq!(
+            paths(&dt, "/**/*[compatible~=\"arm,primecell\"]"),
+            vec!["/soc/uart@7e201000"]
+        );
+        assert_eq!(
+            paths(&dt, "/soc/**/*[status=\"okay\"][pinctrl-0]"),
+            vec!["/soc/sdhost@7e202000"]
+        );
+        assert_eq!(paths(&dt, "/**/i2c0").len(), 1);
+        assert_eq!(paths(&dt, "/cpus/cpu@*").len(), 4);
+    }
+
+    #[test]
+    fn syntax_errors() {
+        assert_eq!(Selector::parse("soc").unwrap_err(), QueryError::NotAbsolute);
+        assert_eq!(
+            Selector::parse("/soc/*[status=okay]").unwrap_err(),
+            QueryError::Syntax(14)
+        );
+        assert!(Selector::parse("/soc//uart").is_err());
+        assert!(Selector::parse("/**[status]").is_err());
+    }
+}
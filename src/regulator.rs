@@ -0,0 +1,175 @@
+//! Regulator supplies
+//!
+//! Consumers reference their power supplies with `<name>-supply` properties
+//! holding a single phandle of a regulator node. Regulators describe their
+//! constraints with `regulator-*` properties and can be supplied by other
+//! regulators in turn, e.g. through `vin-supply`.
+
+use phandle::PhandleError;
+use {DeviceTree, Node, PropError};
+
+/// The constraints of a regulator node.
+#[derive(Debug, PartialEq)]
+pub struct Regulator<'a> {
+    pub node: &'a Node,
+
+    /// The descriptive name from `regulator-name`.
+    pub name: Option<&'a str>,
+
+    pub min_microvolt: Option<u32>,
+    pub max_microvolt: Option<u32>,
+    pub min_microamp: Option<u32>,
+    pub max_microamp: Option<u32>,
+
+    /// The regulator must never be disabled (`regulator-always-on`).
+    pub always_on: bool,
+
+    /// The regulator was enabled by the bootloader (`regulator-boot-on`).
+    pub boot_on: bool,
+}
+
+/// A `<name>-supply` reference.
+#[derive(Debug, PartialEq)]
+pub struct Supply<'a> {
+    /// The supply name, e.g. `vdd` for `vdd-supply`.
+    pub name: &'a str,
+
+    pub regulator: Regulator<'a>,
+}
+
+impl<'a> Regulator<'a> {
+    /// Read the constraints of the regulator `node`.
+    pub fn new(node: &'a Node) -> Regulator<'a> {
+        Regulator {
+            node,
+            name: node.prop_str("regulator-name").ok(),
+            min_microvolt: node.prop_u32("regulator-min-microvolt").ok(),
+            max_microvolt: node.prop_u32("regulator-max-microvolt").ok(),
+            min_microamp: node.prop_u32("regulator-min-microamp").ok(),
+            max_microamp: node.prop_u32("regulator-max-microamp").ok(),
+            always_on: node.has_prop("regulator-always-on"),
+            boot_on: node.has_prop("regulator-boot-on"),
+        }
+    }
+
+    /// The voltage in microvolts if the regulator is fixed, i.e. its
+    /// minimum and maximum are equal.
+    pub fn fixed_microvolt(&self) -> Option<u32> {
+        match (self.min_microvolt, self.max_microvolt) {
+            (Some(min), Some(max)) if min == max => Some(min),
+            _ => None,
+        }
+    }
+}
+
+impl Node {
+    /// All supplies of this node, in property order.
+    pub fn supplies<'a>(&'a self, tree: &'a DeviceTree) -> Result<Vec<Supply<'a>>, PhandleError> {
+        let mut supplies = Vec::new();
+
+        for (prop, _) in self.props.iter() {
+            let len = prop.len();
+            if len <= 7 || !prop.ends_with("-supply") {
+                continue;
+            }
+            supplies.push(Supply {
+                name: &prop[..len - 7],
+                regulator: self.supply_regulator(tree, prop)?,
+            });
+        }
+        Ok(supplies)
+    }
+
+    /// The regulator supplying `name`, e.g. `"vdd"` for `vdd-supply`.
+    pub fn supply<'a>(
+        &self,
+        tree: &'a DeviceTree,
+        name: &str,
+    ) -> Result<Regulator<'a>, PhandleError> {
+        match self.supply_regulator(tree, &format!("{}-supply", name)) {
+            Err(PhandleError::PropError(PropError::NotFound)) => Err(PhandleError::NotFound),
+            result => result,
+        }
+    }
+
+    fn supply_regulator<'a>(
+        &self,
+        tree: &'a DeviceTree,
+        prop: &str,
+    ) -> Result<Regulator<'a>, PhandleError> {
+        let phandle = self.prop_u32(prop)?;
+        let node = tree
+            .find_phandle(phandle)
+            .ok_or(PhandleError::InvalidPhandle(phandle))?;
+        Ok(Regulator::new(node))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testutil::{cells, node, tree};
+
+    #[test]
+    fn supplies() {
+        let root = node(
+            "",
+            vec![],
+            vec![
+                node(
+                    "vcc-5v",
+                    vec![
+                        ("compatible", b"regulator-fixed\0".to_vec()),
+                        ("regulator-name", b"vcc5v\0".to_vec()),
+                        ("regulator-min-microvolt", cells(&[5_000_000])),
+                        ("regulator-max-microvolt", cells(&[5_000_000])),
+                        ("regulator-always-on", vec![]),
+                        ("phandle", cells(&[1])),
+                    ],
+                    vec![],
+                ),
+                node(
+                    "ldo1",
+                    vec![
+                        ("regulator-min-microvolt", cells(&[1_800_000])),
+                        ("regulator-max-microvolt", cells(&[3_300_000])),
+                        ("vin-supply", cells(&[1])),
+                        ("phandle", cells(&[2])),
+                    ],
+                    vec![],
+                ),
+                node(
+                    "mmc",
+                    vec![
+                        ("vmmc-supply", cells(&[1])),
+                        ("vqmmc-supply", cells(&[2])),
+                        ("broken-supply", cells(&[3])),
+                    ],
+                    vec![],
+                ),
+            ],
+        );
+        let dt = tree(root);
+        let mmc = dt.find("/mmc").unwrap();
+
+        let vmmc = mmc.supply(&dt, "vmmc").unwrap();
+        assert_eq!(vmmc.name, Some("vcc5v"));
+        assert_eq!(vmmc.fixed_microvolt(), Some(5_000_000));
+        assert!(vmmc.always_on);
+
+        let vqmmc = mmc.supply(&dt, "vqmmc").unwrap();
+        assert_eq!(vqmmc.fixed_microvolt(), None);
+        assert_eq!(vqmmc.max_microvolt, Some(3_300_000));
+        assert_eq!(vqmmc.node.supplies(&dt).unwrap()[0].name, "vin");
+        assert_eq!(vqmmc.node.supply(&dt, "vin").unwrap().node.name, "vcc-5v");
+
+        assert!(matches!(
+            mmc.supply(&dt, "vdd"),
+            Err(PhandleError::NotFound)
+        ));
+        assert!(matches!(
+            mmc.supplies(&dt),
+            Err(PhandleError::InvalidPhandle(3))
+        ));
+    }
+}
@@ -0,0 +1,290 @@
+//! The `/reserved-memory` node
+//!
+//! Each child of `/reserved-memory` reserves a region of memory, either at a
+//! fixed location given by `reg`, or dynamically by giving a `size` and
+//! optionally an `alignment` and `alloc-ranges` to place it in. Dynamic
+//! regions are placed by the bootloader (or early kernel code), which then
+//! records the chosen location in `reg`.
+
+use memory::{self, Region};
+use {cells_to_u64, DeviceTree, PropError};
+
+/// Alignment used for dynamic regions without an `alignment` property.
+pub const DEFAULT_ALIGNMENT: u64 = 0x1000;
+
+/// An error encountered while allocating dynamic regions.
+#[derive(Debug)]
+pub enum ReservedMemoryError {
+    /// A property could not be read or written.
+    PropError(PropError),
+
+    /// There was not enough free memory to place the named region.
+    NoSpace(String),
+}
+
+/// A single child of `/reserved-memory`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReservedMemory {
+    /// The node name.
+    pub name: String,
+
+    /// Static location(s) from `reg`. Empty for dynamic regions that have
+    /// not been placed yet.
+    pub reg: Vec<Region>,
+
+    /// Size of a dynamic region.
+    pub size: Option<u64>,
+
+    /// Required alignment of a dynamic region.
+    pub alignment: Option<u64>,
+
+    /// Ranges a dynamic region must be placed in. Empty if unrestricted.
+    pub alloc_ranges: Vec<Region>,
+
+    /// The region must not be mapped by the operating system (`no-map`).
+    pub no_map: bool,
+
+    /// The operating system may use the region as long as the owning driver
+    /// can reclaim it (`reusable`).
+    pub reusable: bool,
+
+    /// The `compatible` strings, e.g. `shared-dma-pool`.
+    pub compatible: Vec<String>,
+}
+
+impl ReservedMemory {
+    /// Whether the region still needs to be placed.
+    pub fn is_dynamic(&self) -> bool {
+        self.reg.is_empty() && self.size.is_some()
+    }
+}
+
+impl From<PropError> for ReservedMemoryError {
+    fn from(e: PropError) -> ReservedMemoryError {
+        ReservedMemoryError::PropError(e)
+    }
+}
+
+impl DeviceTree {
+    /// All enabled children of `/reserved-memory`.
+    pub fn reserved_memory(&self) -> Result<Vec<ReservedMemory>, PropError> {
+        let parent = match self.root.child("reserved-memory") {
+            Some(parent) => parent,
+            None => return Ok(Vec::new()),
+        };
+        let (address_cells, size_cells) = (parent.address_cells(), parent.size_cells());
+
+        let mut regions = Vec::new();
+        for node in parent.children.iter().filter(|n| n.is_enabled()) {
+            let reg = match node.prop_reg("reg", address_cells, size_cells) {
+                Ok(reg) => reg,
+                Err(PropError::NotFound) => Vec::new(),
+                Err(e) => return Err(e),
+            };
+            let alloc_ranges = match node.prop_reg("alloc-ranges", address_cells, size_cells) {
+                Ok(ranges) => ranges,
+                Err(PropError::NotFound) => Vec::new(),
+                Err(e) => return Err(e),
+            };
+
+            regions.push(ReservedMemory {
+                name: node.name.clone(),
+                reg: to_regions(reg),
+                size: read_optional(node.prop_cells("size"))?,
+                alignment: read_optional(node.prop_cells("alignment"))?,
+                alloc_ranges: to_regions(alloc_ranges),
+                no_map: node.has_prop("no-map"),
+                reusable: node.has_prop("reusable"),
+                compatible: node.compatible().iter().map(|c| c.to_string()).collect(),
+            });
+        }
+        Ok(regions)
+    }
+
+    /// Place all dynamic `/reserved-memory` regions and write their location
+    /// to `reg`.
+    ///
+    /// Regions are placed in tree order, each at the highest suitably
+    /// aligned address within its `alloc-ranges` that does not overlap
+    /// reserved memory or a previously placed region, like the kernel's
+    /// early reservation code. Returns the names and locations of the
+    /// regions that were placed.
+    pub fn allocate_reserved_memory(
+        &mut self,
+    ) -> Result<Vec<(String, Region)>, ReservedMemoryError> {
+        let mut free = self.memory_map()?.usable;
+        let mut placed = Vec::new();
+
+        for region in self.reserved_memory()? {
+            if !region.is_dynamic() {
+                continue;
+            }
+
+            let size = region.size.unwrap_or(0);
+            let alignment = region.alignment.unwrap_or(DEFAULT_ALIGNMENT).max(1);
+            let candidates = if region.alloc_ranges.is_empty() {
+                free.clone()
+            } else {
+                let mut ranges = region.alloc_ranges.clone();
+                memory::merge(&mut ranges);
+                intersect(&free, &ranges)
+            };
+
+            let address = candidates
+                .iter()
+                .filter_map(|c| place_top_down(c, size, alignment))
+                .max()
+                .ok_or_else(|| ReservedMemoryError::NoSpace(region.name.clone()))?;
+            let allocated = Region::new(address, size);
+
+            free = memory::subtract(&free, &[allocated]);
+            placed.push((region.name, allocated));
+        }
+
+        if let Some(parent) = self.root.child_mut("reserved-memory") {
+            let (address_cells, size_cells) = (parent.address_cells(), parent.size_cells());
+
+            for &(ref name, region) in placed.iter() {
+                if let Some(node) = parent.children.iter_mut().find(|n| n.name == *name) {
+                    node.set_prop_reg(
+                        "reg",
+                        &[(region.address, region.size)],
+                        address_cells,
+                        size_cells,
+                    )?;
+                }
+            }
+        }
+
+        Ok(placed)
+    }
+}
+
+/// The highest address in `range` where `size` bytes aligned to `alignment`
+/// fit.
+fn place_top_down(range: &Region, size: u64, alignment: u64) -> Option<u64> {
+    let top = range.end().checked_sub(size)?;
+    let address = top - top % alignment;
+    if address < range.address {
+        return None;
+    }
+    Some(address)
+}
+
+/// The parts of `a` that are also in `b`. Both must be sorted and merged.
+fn intersect(a: &[Region], b: &[Region]) -> Vec<Region> {
+    let mut result = Vec::new();
+    for x in a.iter() {
+        for y in b.iter().filter(|y| y.overlaps(x)) {
+            let start = x.address.max(y.address);
+            let end = x.end().min(y.end());
+            result.push(Region::new(start, end - start));
+        }
+    }
+    result
+}
+
+fn to_regions(pairs: Vec<(u64, u64)>) -> Vec<Region> {
+    pairs
+        .into_iter()
+        .map(|(address, size)| Region::new(address, size))
+        .collect()
+}
+
+fn read_optional(cells: Result<Vec<u32>, PropError>) -> Result<Option<u64>, PropError> {
+    match cells {
+        Ok(cells) => Ok(Some(cells_to_u64(&cells)?)),
+        Err(PropError::NotFound) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testutil::{cells, node, tree};
+
+    #[test]
+    fn allocate() {
+        let root = node(
+            "",
+            vec![
+                ("#address-cells", cells(&[1])),
+                ("#size-cells", cells(&[1])),
+            ],
+            vec![
+                node(
+                    "memory@0",
+                    vec![
+                        ("device_type", b"memory\0".to_vec()),
+                        ("reg", cells(&[0, 0x4000_0000])),
+                    ],
+                    vec![],
+                ),
+                node(
+                    "reserved-memory",
+                    vec![
+                        ("#address-cells", cells(&[1])),
+                        ("#size-cells", cells(&[1])),
+                        ("ranges", vec![]),
+                    ],
+                    vec![
+                        node(
+                            "fb@3e000000",
+                            vec![
+                                ("reg", cells(&[0x3e00_0000, 0x0200_0000])),
+                                ("no-map", vec![]),
+                            ],
+                            vec![],
+                        ),
+                        node(
+                            "linux,cma",
+                            vec![
+                                ("compatible", b"shared-dma-pool\0".to_vec()),
+                                ("size", cells(&[0x0400_0000])),
+                                ("alignment", cells(&[0x0040_0000])),
+                                ("reusable", vec![]),
+                            ],
+                            vec![],
+                        ),
+                        node(
+                            "ramoops",
+                            vec![
+                                ("size", cells(&[0x0010_0000])),
+                                ("alloc-ranges", cells(&[0x0100_0000, 0x0100_0000])),
+                            ],
+                            vec![],
+                        ),
+                    ],
+                ),
+            ],
+        );
+        let mut dt = tree(root);
+
+        let regions = dt.reserved_memory().unwrap();
+        assert_eq!(regions.len(), 3);
+        assert!(!regions[0].is_dynamic());
+        assert!(regions[1].is_dynamic());
+        assert_eq!(regions[1].compatible, vec!["shared-dma-pool"]);
+
+        let placed = dt.allocate_reserved_memory().unwrap();
+        assert_eq!(
+            placed,
+            vec![
+                (
+                    "linux,cma".to_owned(),
+                    Region::new(0x3a00_0000, 0x0400_0000)
+                ),
+                ("ramoops".to_owned(), Region::new(0x01f0_0000, 0x0010_0000)),
+            ]
+        );
+
+        let cma = dt.find("/reserved-memory/linux,cma").unwrap();
+        assert_eq!(
+            cma.prop_cells("reg").unwrap(),
+            vec![0x3a00_0000, 0x0400_0000]
+        );
+        assert_eq!(dt.memory_map().unwrap().reserved.len(), 3);
+        assert!(dt.allocate_reserved_memory().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,430 @@
+//! Serde support, enabled by the `serde` feature
+//!
+//! Trees are represented in a form that is easy to write by hand, e.g. in
+//! YAML:
+//!
+//! ```text
+//! version: 17
+//! boot_cpuid_phys: 0
+//! reserved: [[0, 0]]
+//! root:
+//!   "#address-cells": 1
+//!   "#size-cells": 1
+//!   compatible: ["acme,board", "acme,soc"]
+//!   model: "Acme board"
+//!   memory@0:
+//!     device_type: "memory"
+//!     reg: [0, 268435456]
+//!     dma-coherent: true
+//! ```
+//!
+//! A node is a map of its properties followed by its children, keyed by
+//! name; entries whose value is a map are children. Property values are
+//!
+//! * `true` for empty properties,
+//! * strings or lists of strings for NUL-terminated strings,
+//! * numbers or lists of numbers for 32 bit cells, with numbers above
+//!   `u32::MAX` stored as a single 64 bit value,
+//! * strings in `dtc` byte string syntax, like `"[0a 0b 0c]"`, for anything
+//!   else.
+//!
+//! Properties are serialized as `PropValue::guess` decodes them, and strings
+//! that look like byte strings are written as a list of one string, so trees
+//! round-trip unchanged. Omitted tree fields default to version 17, boot CPU
+//! 0 and an empty reserved memory map (a single `(0, 0)` terminator, which,
+//! like in loaded trees, is part of `reserved`).
+//!
+//! Node names are the keys of their parent; a node deserialized on its own
+//! has an empty name. The layout of a loaded blob is not serialized.
+
+use core::fmt;
+
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::{self, Serialize, SerializeMap, SerializeStruct, Serializer};
+
+use value::PropValue;
+use {bytes_to_cells, DeviceTree, Node, SUPPORTED_VERSION};
+
+const FIELDS: &[&str] = &["version", "boot_cpuid_phys", "reserved", "root"];
+
+/// An entry of a node map.
+enum Entry {
+    Prop(PropValue),
+    Child(Node),
+}
+
+/// An element of a property list.
+enum Item {
+    Cell(u64),
+    Str(String),
+}
+
+struct PropValueVisitor;
+struct EntryVisitor;
+struct ItemVisitor;
+struct NodeVisitor;
+struct DeviceTreeVisitor;
+
+impl Serialize for PropValue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            PropValue::Empty => serializer.serialize_bool(true),
+            PropValue::U32(val) | PropValue::PhandleRef(val) => serializer.serialize_u32(val),
+            PropValue::U64(val) if val > u64::from(u32::MAX) => serializer.serialize_u64(val),
+            PropValue::String(ref val) if parse_bytes(val).is_none() => {
+                serializer.serialize_str(val)
+            }
+            PropValue::String(ref val) => serializer.collect_seq(Some(val)),
+            PropValue::StringList(ref vals) => serializer.collect_seq(vals),
+            PropValue::Bytes(_) => serializer.collect_str(self),
+            PropValue::Cells(ref cells) => serializer.collect_seq(cells),
+            _ => {
+                let cells = self
+                    .encode()
+                    .and_then(|raw| bytes_to_cells(&raw))
+                    .map_err(|e| ser::Error::custom(format!("{:?}", e)))?;
+                serializer.collect_seq(cells)
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PropValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<PropValue, D::Error> {
+        deserializer.deserialize_any(PropValueVisitor)
+    }
+}
+
+impl<'de> Visitor<'de> for PropValueVisitor {
+    type Value = PropValue;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a property value")
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<PropValue, E> {
+        if !v {
+            return Err(E::invalid_value(de::Unexpected::Bool(v), &"true"));
+        }
+        Ok(PropValue::Empty)
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<PropValue, E> {
+        if v > u64::from(u32::MAX) {
+            return Ok(PropValue::U64(v));
+        }
+        Ok(PropValue::U32(v as u32))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<PropValue, E> {
+        if v < 0 {
+            return Err(E::invalid_value(de::Unexpected::Signed(v), &self));
+        }
+        self.visit_u64(v as u64)
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<PropValue, E> {
+        Ok(match parse_bytes(v) {
+            Some(bytes) => PropValue::Bytes(bytes),
+            None => PropValue::String(v.to_owned()),
+        })
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<PropValue, A::Error> {
+        let mut cells = Vec::new();
+        let mut strings = Vec::new();
+
+        while let Some(item) = seq.next_element()? {
+            match item {
+                Item::Cell(cell) if cell <= u64::from(u32::MAX) => cells.push(cell as u32),
+                Item::Cell(cell) => {
+                    return Err(de::Error::invalid_value(
+                        de::Unexpected::Unsigned(cell),
+                        &"a 32 bit cell",
+                    ))
+                }
+                Item::Str(s) => strings.push(s),
+            }
+            if !cells.is_empty() && !strings.is_empty() {
+                return Err(de::Error::custom("list mixes cells and strings"));
+            }
+        }
+
+        if strings.is_empty() {
+            return Ok(PropValue::Cells(cells));
+        }
+        Ok(PropValue::StringList(strings))
+    }
+}
+
+impl<'de> Deserialize<'de> for Item {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Item, D::Error> {
+        deserializer.deserialize_any(ItemVisitor)
+    }
+}
+
+impl<'de> Visitor<'de> for ItemVisitor {
+    type Value = Item;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a cell or a string")
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Item, E> {
+        Ok(Item::Cell(v))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Item, E> {
+        if v < 0 {
+            return Err(E::invalid_value(de::Unexpected::Signed(v), &self));
+        }
+        Ok(Item::Cell(v as u64))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Item, E> {
+        Ok(Item::Str(v.to_owned()))
+    }
+}
+
+impl<'de> Deserialize<'de> for Entry {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Entry, D::Error> {
+        deserializer.deserialize_any(EntryVisitor)
+    }
+}
+
+impl<'de> Visitor<'de> for EntryVisitor {
+    type Value = Entry;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a property value or a node")
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<Entry, E> {
+        PropValueVisitor.visit_bool(v).map(Entry::Prop)
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Entry, E> {
+        PropValueVisitor.visit_u64(v).map(Entry::Prop)
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Entry, E> {
+        PropValueVisitor.visit_i64(v).map(Entry::Prop)
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Entry, E> {
+        PropValueVisitor.visit_str(v).map(Entry::Prop)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Entry, A::Error> {
+        PropValueVisitor.visit_seq(seq).map(Entry::Prop)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Entry, A::Error> {
+        NodeVisitor.visit_map(map).map(Entry::Child)
+    }
+}
+
+impl Serialize for Node {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.props.len() + self.children.len()))?;
+        for (name, raw) in self.props.iter() {
+            map.serialize_entry(name, &PropValue::guess(raw))?;
+        }
+        for child in self.children.iter() {
+            map.serialize_entry(&child.name, child)?;
+        }
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Node {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Node, D::Error> {
+        deserializer.deserialize_map(NodeVisitor)
+    }
+}
+
+impl<'de> Visitor<'de> for NodeVisitor {
+    type Value = Node;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a map of properties and child nodes")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Node, A::Error> {
+        let mut node = Node {
+            name: String::new(),
+            props: Vec::new(),
+            children: Vec::new(),
+        };
+
+        while let Some((name, entry)) = map.next_entry::<String, Entry>()? {
+            match entry {
+                Entry::Prop(value) => {
+                    let raw = value
+                        .encode()
+                        .map_err(|e| de::Error::custom(format!("{:?}", e)))?;
+                    node.props.push((name, raw));
+                }
+                Entry::Child(mut child) => {
+                    child.name = name;
+                    node.children.push(child);
+                }
+            }
+        }
+        Ok(node)
+    }
+}
+
+impl Serialize for DeviceTree {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tree = serializer.serialize_struct("DeviceTree", FIELDS.len())?;
+        tree.serialize_field("version", &self.version)?;
+        tree.serialize_field("boot_cpuid_phys", &self.boot_cpuid_phys)?;
+        tree.serialize_field("reserved", &self.reserved)?;
+        tree.serialize_field("root", &self.root)?;
+        tree.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for DeviceTree {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<DeviceTree, D::Error> {
+        deserializer.deserialize_struct("DeviceTree", FIELDS, DeviceTreeVisitor)
+    }
+}
+
+impl<'de> Visitor<'de> for DeviceTreeVisitor {
+    type Value = DeviceTree;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a device tree")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<DeviceTree, A::Error> {
+        let mut version = None;
+        let mut boot_cpuid_phys = None;
+        let mut reserved = None;
+        let mut root = None;
+
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "version" => version = Some(map.next_value()?),
+                "boot_cpuid_phys" => boot_cpuid_phys = Some(map.next_value()?),
+                "reserved" => reserved = Some(map.next_value()?),
+                "root" => root = Some(map.next_value()?),
+                _ => return Err(de::Error::unknown_field(&key, FIELDS)),
+            }
+        }
+
+        Ok(DeviceTree {
+            version: version.unwrap_or(SUPPORTED_VERSION),
+            boot_cpuid_phys: boot_cpuid_phys.unwrap_or(0),
+            reserved: reserved.unwrap_or_else(|| vec![(0, 0)]),
+            root: root.ok_or_else(|| de::Error::missing_field("root"))?,
+            layout: None,
+        })
+    }
+}
+
+/// Parse a byte string in `dtc` syntax, hex digit pairs in brackets,
+/// optionally separated by whitespace.
+fn parse_bytes(s: &str) -> Option<Vec<u8>> {
+    let inner = s.strip_prefix('[')?.strip_suffix(']')?;
+
+    let mut bytes = Vec::new();
+    for group in inner.split_whitespace() {
+        if group.len() % 2 != 0 || !group.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        for i in (0..group.len()).step_by(2) {
+            bytes.push(u8::from_str_radix(&group[i..(i + 2)], 16).ok()?);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json;
+    use testutil::{cells, node, rpi, tree};
+    use DeviceTree;
+
+    #[test]
+    fn rpi_roundtrip() {
+        let dt = rpi();
+        let json = serde_json::to_string(&dt).unwrap();
+        let back: DeviceTree = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, dt);
+        assert_eq!(DeviceTree::load(&back.store().unwrap()).unwrap(), dt);
+    }
+
+    #[test]
+    fn readable_form() {
+        let dt = tree(node(
+            "",
+            vec![
+                ("#address-cells", cells(&[1])),
+                ("compatible", b"acme,board\0acme,soc\0".to_vec()),
+                ("model", b"[ab]\0".to_vec()),
+            ],
+            vec![node(
+                "memory@0",
+                vec![
+                    ("reg", cells(&[0, 0x1000])),
+                    ("dma-coherent", vec![]),
+                    ("mac", vec![0, 0x11, 0xab]),
+                ],
+                vec![],
+            )],
+        ));
+
+        let json = serde_json::to_string(&dt).unwrap();
+        assert_eq!(
+            json,
+            concat!(
+                r##"{"version":17,"boot_cpuid_phys":0,"reserved":[],"root":{"#address-cells":1,"##,
+                r##""compatible":["acme,board","acme,soc"],"model":["[ab]"],"##,
+                r##""memory@0":{"reg":[0,4096],"dma-coherent":true,"mac":"[00 11 ab]"}}}"##
+            )
+        );
+        let back: DeviceTree = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, dt);
+    }
+
+    #[test]
+    fn handwritten() {
+        let dt: DeviceTree = serde_json::from_str(
+            r#"{"root": {
+                "model": "board",
+                "big": 4294967296,
+                "mac": "[0011 ab]",
+                "cpus": {"cpu@0": {"reg": 0}}
+            }}"#,
+        )
+        .unwrap();
+
+        assert_eq!(dt.version, 17);
+        assert_eq!(dt.reserved, vec![(0, 0)]);
+        assert_eq!(dt.root.name, "");
+        assert_eq!(dt.root.prop_str("model").unwrap(), "board");
+        assert_eq!(dt.root.prop_u64("big").unwrap(), 1 << 32);
+        assert_eq!(dt.root.prop_raw("mac").unwrap(), &vec![0, 0x11, 0xab]);
+        assert_eq!(
+            dt.find("/cpus/cpu@0").unwrap().prop_cells("reg").unwrap(),
+            vec![0]
+        );
+        DeviceTree::load(&dt.store().unwrap()).unwrap();
+
+        for bad in [
+            r#"{"version": 17}"#,
+            r#"{"root": {"a": [1, "b"]}}"#,
+            r#"{"root": {"a": [4294967296]}}"#,
+            r#"{"root": {"a": -1}}"#,
+            r#"{"root": {"a": false}}"#,
+            r#"{"root": {}, "size": 1}"#,
+        ]
+        .iter()
+        {
+            assert!(serde_json::from_str::<DeviceTree>(bad).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//! The `status` property
+//!
+//! `status` tells whether a device is operational. Nodes without the property
+//! are treated as `"okay"`. Disabled nodes should not be probed, and neither
+//! should their children.
+
+use walk::PreOrder;
+use {DeviceTree, Node};
+
+/// The decoded value of a `status` property.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status<'a> {
+    /// The device is operational (`"okay"`, or no `status` at all).
+    Okay,
+
+    /// The device is not operational, but might become so later.
+    Disabled,
+
+    /// The device is operational, but should not be used, e.g. because it
+    /// is controlled by other software.
+    Reserved,
+
+    /// The device is not operational because of a serious error.
+    Fail,
+
+    /// Like `Fail`, with a device-specific error condition (`"fail-sss"`).
+    FailCondition(&'a str),
+
+    /// Any other value.
+    Other(&'a str),
+}
+
+/// Pre-order iterator that skips disabled subtrees, see
+/// `Node::enabled_nodes`.
+pub struct EnabledNodes<'a> {
+    nodes: PreOrder<'a>,
+    skip_below: Option<usize>,
+}
+
+impl<'a> Status<'a> {
+    fn parse(value: &'a str) -> Status<'a> {
+        match value {
+            "okay" | "ok" => Status::Okay,
+            "disabled" => Status::Disabled,
+            "reserved" => Status::Reserved,
+            "fail" => Status::Fail,
+            _ if value.starts_with("fail-") => Status::FailCondition(&value[5..]),
+            _ => Status::Other(value),
+        }
+    }
+
+    /// The value as stored in the `status` property.
+    pub fn as_string(&self) -> String {
+        match *self {
+            Status::Okay => "okay".to_owned(),
+            Status::Disabled => "disabled".to_owned(),
+            Status::Reserved => "reserved".to_owned(),
+            Status::Fail => "fail".to_owned(),
+            Status::FailCondition(condition) => format!("fail-{}", condition),
+            Status::Other(value) => value.to_owned(),
+        }
+    }
+}
+
+impl Node {
+    /// The decoded `status` property. Missing or unreadable values are
+    /// treated as `Status::Okay`.
+    pub fn status(&self) -> Status<'_> {
+        match self.prop_str("status") {
+            Ok(value) => Status::parse(value),
+            Err(_) => Status::Okay,
+        }
+    }
+
+    /// Whether the device is operational and should be probed.
+    pub fn is_enabled(&self) -> bool {
+        self.status() == Status::Okay
+    }
+
+    pub fn set_status(&mut self, status: Status) {
+        let value = status.as_string();
+        self.set_prop_str("status", &value);
+    }
+
+    /// Set `status` to `"okay"`.
+    pub fn enable(&mut self) {
+        self.set_status(Status::Okay);
+    }
+
+    /// Set `status` to `"disabled"`.
+    pub fn disable(&mut self) {
+        self.set_status(Status::Disabled);
+    }
+
+    /// Iterate over this node and its descendants in pre-order, yielding
+    /// `(depth, node)` and skipping nodes that are not enabled along with
+    /// their children.
+    pub fn enabled_nodes(&self) -> EnabledNodes<'_> {
+        EnabledNodes {
+            nodes: self.pre_order(),
+            skip_below: None,
+        }
+    }
+}
+
+impl DeviceTree {
+    /// Iterate over all enabled nodes, see `Node::enabled_nodes`.
+    pub fn enabled_nodes(&self) -> EnabledNodes<'_> {
+        self.root.enabled_nodes()
+    }
+}
+
+impl<'a> Iterator for EnabledNodes<'a> {
+    type Item = (usize, &'a Node);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for (depth, node) in self.nodes.by_ref() {
+            if let Some(skip_depth) = self.skip_below {
+                if depth > skip_depth {
+                    continue;
+                }
+                self.skip_below = None;
+            }
+
+            if !node.is_enabled() {
+                self.skip_below = Some(depth);
+                continue;
+            }
+
+            return Some((depth, node));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testutil::rpi;
+
+    #[test]
+    fn status_values() {
+        let mut dt = rpi();
+
+        assert_eq!(dt.find("/audio").unwrap().status(), Status::Disabled);
+        assert_eq!(dt.find("/soc/rng@7e104000").unwrap().status(), Status::Okay);
+        assert!(dt.find("/soc/usb@7e980000").unwrap().is_enabled());
+
+        let spi = dt.find_mut("/soc/spi@7e204000").unwrap();
+        spi.set_status(Status::FailCondition("overheat"));
+        assert_eq!(spi.prop_str("status").unwrap(), "fail-overheat");
+        assert_eq!(spi.status(), Status::FailCondition("overheat"));
+    }
+
+    #[test]
+    fn skip_disabled() {
+        let mut dt = rpi();
+
+        let enabled: Vec<&str> = dt.enabled_nodes().map(|(_, n)| n.name.as_str()).collect();
+        assert!(enabled.contains(&"uart@7e201000"));
+        assert!(!enabled.contains(&"spi@7e204000"));
+        assert!(!enabled.contains(&"spidev@0"));
+        assert!(enabled.contains(&"cpus"));
+
+        dt.find_mut("/soc/spi@7e204000").unwrap().enable();
+        dt.find_mut("/soc").unwrap().disable();
+        let enabled: Vec<&str> = dt.enabled_nodes().map(|(_, n)| n.name.as_str()).collect();
+        assert!(!enabled.contains(&"spidev@0"));
+        assert!(!enabled.contains(&"uart@7e201000"));
+        assert!(enabled.contains(&"clock@0"));
+    }
+}
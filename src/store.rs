@@ -0,0 +1,265 @@
+//! Serialization options
+//!
+//! By default, `DeviceTree::store` produces a minimal blob. Firmware that
+//! edits the tree in place (adding `/chosen` properties or memory nodes, like
+//! U-Boot's `fdt resize`) needs free space at its end, which is what `dtc -p`,
+//! `-S` and `-a` provide. `StoreOptions` offers the same knobs.
+//!
+//! Trees loaded from a blob remember its layout: the block offsets and
+//! order, the free space, `NOP` tags and the strings block. Storing such a
+//! tree reproduces the original blob byte for byte as long as it was not
+//! modified, which matters when blobs are signed. Modified trees keep as
+//! much of the layout as still fits, and the total size unless the tree
+//! shrank. Call `DeviceTree::clear_layout` to get a fresh, minimal layout
+//! instead.
+
+use util::{SizeCounter, SliceWriter, VecWrite};
+use {DeviceTree, DeviceTreeError};
+
+/// The order of the structure and strings blocks. The memory reservation
+/// block always comes first, right after the header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockOrder {
+    /// Structure block, then strings block, as written by `dtc`.
+    StructStrings,
+
+    /// Strings block, then structure block.
+    StringsStruct,
+}
+
+/// Options for `DeviceTree::store_with` and friends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StoreOptions {
+    /// Minimum total size of the blob in bytes (`dtc -S`).
+    pub min_size: usize,
+
+    /// Free space appended after the last block in bytes (`dtc -p`).
+    pub padding: usize,
+
+    /// The total size is rounded up to a multiple of this (`dtc -a`).
+    pub alignment: usize,
+
+    /// `None` keeps the order of the loaded blob, or puts the structure
+    /// block first for trees without a layout.
+    pub block_order: Option<BlockOrder>,
+
+    /// The version to write, one of 1, 2, 3, 16 and 17. `None` uses
+    /// `DeviceTree::version`.
+    pub version: Option<u32>,
+
+    /// `None` keeps the value of the loaded blob, or uses 16 for versions
+    /// 16 and 17 and 1 before, like `dtc`.
+    pub last_comp_version: Option<u32>,
+}
+
+impl Default for StoreOptions {
+    fn default() -> StoreOptions {
+        StoreOptions {
+            min_size: 0,
+            padding: 0,
+            alignment: 1,
+            block_order: None,
+            version: None,
+            last_comp_version: None,
+        }
+    }
+}
+
+impl DeviceTree {
+    /// Serialize the tree using `options`.
+    pub fn store_with(&self, options: &StoreOptions) -> Result<Vec<u8>, DeviceTreeError> {
+        let mut dtb = Vec::new();
+        self.write(&mut dtb, options)?;
+        Ok(dtb)
+    }
+
+    /// The exact size in bytes of the blob produced by `store_with()`.
+    pub fn measure_with(&self, options: &StoreOptions) -> Result<usize, DeviceTreeError> {
+        let mut counter = SizeCounter::default();
+        self.write(&mut counter, options)?;
+        Ok(counter.offset())
+    }
+
+    /// Serialize into `buf` using `options`, see `store_into()`.
+    pub fn store_into_with(
+        &self,
+        buf: &mut [u8],
+        options: &StoreOptions,
+    ) -> Result<usize, DeviceTreeError> {
+        let size = self.measure_with(options)?;
+        if size > buf.len() {
+            return Err(DeviceTreeError::BufferTooSmall(size));
+        }
+
+        let mut writer = SliceWriter::new(&mut buf[..size]);
+        self.write(&mut writer, options)?;
+        Ok(writer.offset())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testutil::{cells, node, rpi, tree};
+    use util::SliceRead;
+
+    #[test]
+    fn padding_and_order() {
+        let dt = rpi();
+        let minimal = dt.store().unwrap();
+
+        let padded = dt
+            .store_with(&StoreOptions {
+                padding: 100,
+                alignment: 0x1000,
+                ..StoreOptions::default()
+            })
+            .unwrap();
+        assert_eq!(padded.len() % 0x1000, 0);
+        assert!(padded.len() >= minimal.len() + 100);
+        // only totalsize differs, followed by zeroed free space
+        assert_eq!(&padded[8..minimal.len()], &minimal[8..]);
+        assert!(padded[minimal.len()..].iter().all(|&b| b == 0));
+        assert_eq!(DeviceTree::load(&padded).unwrap(), dt);
+
+        let sized = dt
+            .store_with(&StoreOptions {
+                min_size: 0x10000,
+                block_order: Some(BlockOrder::StringsStruct),
+                ..StoreOptions::default()
+            })
+            .unwrap();
+        assert_eq!(sized.len(), 0x10000);
+        let slice = sized.as_slice();
+        assert!(slice.read_be_u32(12).unwrap() < slice.read_be_u32(8).unwrap());
+        assert_eq!(DeviceTree::load(slice).unwrap(), dt);
+
+        let options = StoreOptions {
+            padding: 16,
+            ..StoreOptions::default()
+        };
+        assert_eq!(dt.measure_with(&options).unwrap(), minimal.len() + 16);
+        let mut buf = vec![0xff; minimal.len() + 16];
+        dt.store_into_with(&mut buf, &options).unwrap();
+        assert!(buf[minimal.len()..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn reproduce_layout() {
+        let mut dt = tree(node(
+            "",
+            vec![("a", cells(&[1])), ("compatible", b"test\0".to_vec())],
+            vec![node("b", vec![("a", cells(&[2]))], vec![])],
+        ));
+        dt.reserved = vec![(0, 0)];
+        let mut blob = dt
+            .store_with(&StoreOptions {
+                padding: 20,
+                block_order: Some(BlockOrder::StringsStruct),
+                ..StoreOptions::default()
+            })
+            .unwrap();
+
+        // last_comp_version
+        blob[27] = 2;
+        // turn the first property into NOPs, like fdt_nop_property()
+        let structure = blob.as_slice().read_be_u32(8).unwrap() as usize;
+        for tag in blob[(structure + 8)..(structure + 24)].chunks_mut(4) {
+            tag.copy_from_slice(&[0, 0, 0, 4]);
+        }
+        // move the structure block back, leaving a gap
+        let size = blob.len();
+        blob.splice(structure..structure, vec![0; 8]);
+        blob[11] += 8;
+        blob[7] += 8;
+        assert_eq!(blob.len(), size + 8);
+
+        let mut loaded = DeviceTree::load(&blob).unwrap();
+        assert!(!loaded.root.has_prop("a"));
+        assert_eq!(loaded.store().unwrap(), blob);
+        assert_eq!(loaded.measure().unwrap(), blob.len());
+
+        // changes keep the layout where possible
+        loaded.root.children[0].set_prop("a", cells(&[3, 4]));
+        let changed = loaded.store().unwrap();
+        assert_eq!(&changed[..27], &blob[..27]);
+        assert_eq!(changed.len(), blob.len());
+        assert_eq!(DeviceTree::load(&changed).unwrap(), loaded);
+        assert_eq!(loaded.layout().unwrap().total_size(), blob.len());
+
+        // removing nodes gives up the free space
+        let child = loaded.root.children.pop().unwrap();
+        let shrunk = loaded.store().unwrap();
+        assert!(shrunk.len() < blob.len());
+        assert_eq!(DeviceTree::load(&shrunk).unwrap(), loaded);
+        loaded.root.children.push(child);
+
+        loaded.clear_layout();
+        let fresh = loaded.store().unwrap();
+        assert!(fresh.len() < blob.len());
+        assert_eq!(DeviceTree::load(&fresh).unwrap(), loaded);
+    }
+
+    #[test]
+    fn older_versions() {
+        let dt = rpi();
+        let v16 = dt
+            .store_with(&StoreOptions {
+                version: Some(16),
+                ..StoreOptions::default()
+            })
+            .unwrap();
+        let header = v16.as_slice();
+        assert_eq!(header.read_be_u32(20).unwrap(), 16);
+        assert_eq!(header.read_be_u32(24).unwrap(), 16);
+        // the header ends before size_dt_struct
+        assert_eq!(header.read_be_u32(36).unwrap(), 0);
+        let loaded = DeviceTree::load(&v16).unwrap();
+        assert_eq!(loaded.version, 16);
+        assert_eq!(loaded.root, dt.root);
+        assert_eq!(loaded.store().unwrap(), v16);
+
+        let mut dt = tree(node(
+            "",
+            vec![("a", cells(&[1, 2]))],
+            vec![node("b", vec![], vec![node("c", vec![], vec![])])],
+        ));
+        dt.reserved = vec![(0, 0)];
+        let v2 = dt
+            .store_with(&StoreOptions {
+                version: Some(2),
+                ..StoreOptions::default()
+            })
+            .unwrap();
+        let header = v2.as_slice();
+        assert_eq!(header.read_be_u32(20).unwrap(), 2);
+        assert_eq!(header.read_be_u32(24).unwrap(), 1);
+        // BEGIN_NODE "/", then the property with its value aligned to 8
+        assert_eq!(header.read_be_u32(8).unwrap(), 48);
+        assert_eq!(&v2[52..54], b"/\0");
+        assert_eq!(&v2[72..80], cells(&[1, 2]).as_slice());
+        assert!(v2.windows(5).any(|w| w == b"/b/c\0"));
+
+        let store_as = |dt: &DeviceTree, version, last_comp_version| {
+            dt.store_with(&StoreOptions {
+                version: Some(version),
+                last_comp_version,
+                ..StoreOptions::default()
+            })
+        };
+        assert!(store_as(&dt, 1, None).is_ok());
+        assert!(matches!(
+            store_as(&dt, 4, None),
+            Err(DeviceTreeError::VersionNotSupported)
+        ));
+        assert!(matches!(
+            store_as(&dt, 16, Some(17)),
+            Err(DeviceTreeError::NotRepresentable)
+        ));
+        dt.boot_cpuid_phys = 1;
+        assert!(matches!(
+            store_as(&dt, 1, None),
+            Err(DeviceTreeError::NotRepresentable)
+        ));
+    }
+}
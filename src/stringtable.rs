@@ -0,0 +1,266 @@
+//! The strings block written by `DeviceTree::store`
+//!
+//! By default, property names are written in the order they are used, one
+//! copy per property, which needs no allocation. The compact layout, used
+//! with the `string-dedup` feature, stores every name once and lets names
+//! that are a suffix of another one (`cells` in `#address-cells`) point into
+//! it, like `dtc` does.
+//!
+//! When the tree was loaded from a blob, the original strings block is kept
+//! as is and every property keeps its original name offset as long as the
+//! name did not change, so an unmodified tree produces the same block. Only
+//! names not found in it are appended.
+
+use util::{VecWrite, VecWriteResult};
+use {Layout, Node};
+
+pub struct StringTable<'a> {
+    /// Names are looked up instead of written in property order.
+    compact: bool,
+
+    /// The original strings block, if any.
+    base: &'a [u8],
+
+    /// Every name in `base`, including the suffixes of longer names, with
+    /// the offset of its first occurrence, sorted by name.
+    base_index: Vec<(&'a [u8], u32)>,
+
+    /// The original name offset of every property, in tree order.
+    preserved: &'a [u32],
+
+    /// Names missing from `base`, appended after it.
+    extra: Vec<u8>,
+
+    /// The offsets of the names in `extra`, sorted by name.
+    index: Vec<(&'a str, u32)>,
+
+    /// The number of properties passed to `add_string` so far.
+    count: usize,
+
+    /// In sequential mode, the offset of the next name and the total size.
+    next: usize,
+    size: usize,
+}
+
+impl<'a> StringTable<'a> {
+    /// Prepare the strings block for `root`, reusing the strings block of
+    /// `layout` if given. Layouts always use the compact mode.
+    pub fn new(root: &'a Node, layout: Option<&'a Layout>, compact: bool) -> StringTable<'a> {
+        let compact = compact || layout.is_some();
+        let base = layout.map_or(&[][..], |l| &l.strings[..]);
+        let mut table = StringTable {
+            compact,
+            base,
+            base_index: index_block(base),
+            preserved: layout.map_or(&[][..], |l| &l.name_offsets[..]),
+            extra: Vec::new(),
+            index: Vec::new(),
+            count: 0,
+            next: 0,
+            size: 0,
+        };
+
+        if compact {
+            let mut missing = Vec::new();
+            table.collect_missing(root, &mut missing);
+            table.add_compact(missing);
+            table.count = 0;
+        } else {
+            table.size = sequential_size(root);
+        }
+        table
+    }
+
+    /// The offset of the name of the next property, which must be `val`.
+    ///
+    /// Properties have to be passed in tree order, i.e. the properties of a
+    /// node followed by its children, and `val` must be a property name of
+    /// the tree the table was built for.
+    pub fn add_string(&mut self, val: &str) -> u32 {
+        let index = self.count;
+        self.count += 1;
+
+        if !self.compact {
+            let offset = self.next;
+            self.next += val.len() + 1;
+            return offset as u32;
+        }
+
+        // every name of the tree is in `base` or was added to `extra` by
+        // `new()`, whatever its position
+        self.lookup(index, val)
+            .expect("property name missing from string table")
+    }
+
+    pub fn size(&self) -> usize {
+        if self.compact {
+            self.base.len() + self.extra.len()
+        } else {
+            self.size
+        }
+    }
+
+    pub fn write<W: VecWrite>(&self, out: &mut W, root: &Node) -> VecWriteResult {
+        if self.compact {
+            out.write_bytes(self.base)?;
+            return out.write_bytes(&self.extra);
+        }
+        write_sequential(out, root)
+    }
+
+    fn lookup(&self, index: usize, val: &str) -> Option<u32> {
+        if let Some(&offset) = self.preserved.get(index) {
+            if string_at(self.base, offset as usize) == Some(val.as_bytes()) {
+                return Some(offset);
+            }
+        }
+        if let Ok(i) = self
+            .base_index
+            .binary_search_by(|&(name, _)| name.cmp(val.as_bytes()))
+        {
+            return Some(self.base_index[i].1);
+        }
+        self.index
+            .binary_search_by(|&(name, _)| name.cmp(val))
+            .ok()
+            .map(|i| self.index[i].1)
+    }
+
+    fn collect_missing(&mut self, node: &'a Node, missing: &mut Vec<&'a str>) {
+        for (name, _) in node.props.iter() {
+            let index = self.count;
+            self.count += 1;
+            if self.lookup(index, name).is_none() {
+                missing.push(name);
+            }
+        }
+        for child in node.children.iter() {
+            self.collect_missing(child, missing);
+        }
+    }
+
+    /// Append `names` to `extra`, sharing suffixes.
+    ///
+    /// Sorted by their reversed bytes in descending order, every name that
+    /// is a suffix of another one directly follows a name ending with it.
+    fn add_compact(&mut self, mut names: Vec<&'a str>) {
+        names.sort_by(|a, b| b.bytes().rev().cmp(a.bytes().rev()));
+        names.dedup();
+
+        let mut prev: Option<(&str, usize)> = None;
+        for name in names {
+            let offset = match prev {
+                Some((longer, end)) if longer.ends_with(name) => end - name.len(),
+                _ => {
+                    self.extra.extend_from_slice(name.as_bytes());
+                    self.extra.push(0);
+                    let end = self.base.len() + self.extra.len() - 1;
+                    prev = Some((name, end));
+                    end - name.len()
+                }
+            };
+            self.index.push((name, offset as u32));
+        }
+        self.index.sort();
+    }
+}
+
+fn sequential_size(node: &Node) -> usize {
+    let own: usize = node.props.iter().map(|(name, _)| name.len() + 1).sum();
+    own + node.children.iter().map(sequential_size).sum::<usize>()
+}
+
+fn write_sequential<W: VecWrite>(out: &mut W, node: &Node) -> VecWriteResult {
+    for (name, _) in node.props.iter() {
+        out.write_bstring0(name)?;
+    }
+    for child in node.children.iter() {
+        write_sequential(out, child)?;
+    }
+    Ok(())
+}
+
+/// The string at `offset` of a strings block, without its terminator.
+fn string_at(block: &[u8], offset: usize) -> Option<&[u8]> {
+    let rest = block.get(offset..)?;
+    rest.iter().position(|&b| b == 0).map(|len| &rest[..len])
+}
+
+/// Every string of a strings block and every suffix of one, with the offset
+/// of its first occurrence, sorted by string.
+fn index_block(block: &[u8]) -> Vec<(&[u8], u32)> {
+    let mut index = Vec::new();
+    let mut start = 0;
+    for (end, _) in block.iter().enumerate().filter(|&(_, &b)| b == 0) {
+        for offset in start..=end {
+            index.push((&block[offset..end], offset as u32));
+        }
+        start = end + 1;
+    }
+    index.sort();
+    index.dedup_by_key(|&mut (name, _)| name);
+    index
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testutil::{node, rpi};
+    use util::SliceRead;
+    use DeviceTree;
+
+    #[test]
+    fn shared_suffixes() {
+        let root = node(
+            "",
+            vec![("#address-cells", vec![]), ("#size-cells", vec![])],
+            vec![node(
+                "cpus",
+                vec![("cells", vec![]), ("#size-cells", vec![])],
+                vec![],
+            )],
+        );
+
+        let mut strings = StringTable::new(&root, None, true);
+        assert_eq!(strings.size(), 15 + 12);
+
+        let mut block = Vec::new();
+        strings.write(&mut block, &root).unwrap();
+        for name in ["#address-cells", "#size-cells", "cells", "#size-cells"].iter() {
+            let offset = strings.add_string(name) as usize;
+            assert_eq!(string_at(&block, offset), Some(name.as_bytes()));
+        }
+
+        let sequential = StringTable::new(&root, None, false);
+        assert_eq!(sequential.size(), 15 + 12 + 6 + 12);
+
+        // suffixes point to their first occurrence
+        assert_eq!(
+            index_block(b"ab\0b\0"),
+            vec![(&b""[..], 2), (&b"ab"[..], 0), (&b"b"[..], 1)]
+        );
+    }
+
+    #[test]
+    fn keep_original_offsets() {
+        let buf = include_bytes!("../examples/bcm2709-rpi-2-b.dtb");
+        let strings_block = |dtb: &[u8]| {
+            let start = dtb.read_be_u32(12).unwrap() as usize;
+            let size = dtb.read_be_u32(32).unwrap() as usize;
+            dtb[start..(start + size)].to_vec()
+        };
+
+        let mut dt = rpi();
+        let dtb = dt.store().unwrap();
+        assert_eq!(strings_block(&dtb), strings_block(buf));
+
+        dt.root.set_prop_str("model-name", "test");
+        let dtb = dt.store().unwrap();
+        let block = strings_block(&dtb);
+        assert_eq!(&block[..(block.len() - 11)], strings_block(buf).as_slice());
+        assert_eq!(DeviceTree::load(&dtb).unwrap(), dt);
+
+        dt.clear_layout();
+        assert_eq!(DeviceTree::load(&dt.store().unwrap()).unwrap(), dt);
+    }
+}
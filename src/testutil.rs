@@ -0,0 +1,34 @@
+//! Helpers for building small trees in tests.
+
+use {DeviceTree, Node};
+
+pub fn cells(vals: &[u32]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for v in vals {
+        buf.extend_from_slice(&[(v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8, *v as u8]);
+    }
+    buf
+}
+
+pub fn node(name: &str, props: Vec<(&str, Vec<u8>)>, children: Vec<Node>) -> Node {
+    Node {
+        name: name.to_owned(),
+        props: props.into_iter().map(|(k, v)| (k.to_owned(), v)).collect(),
+        children,
+    }
+}
+
+pub fn tree(root: Node) -> DeviceTree {
+    DeviceTree {
+        version: 17,
+        boot_cpuid_phys: 0,
+        reserved: vec![],
+        root,
+        layout: None,
+    }
+}
+
+pub fn rpi() -> DeviceTree {
+    let buf = include_bytes!("../examples/bcm2709-rpi-2-b.dtb");
+    DeviceTree::load(buf).unwrap()
+}
@@ -19,10 +19,10 @@ pub trait SliceRead {
     fn subslice(&self, start: usize, len: usize) -> SliceReadResult<&[u8]>;
 }
 
-impl<'a> SliceRead for &'a [u8] {
+impl SliceRead for &[u8] {
     fn read_be_u32(&self, pos: usize) -> SliceReadResult<u32> {
         // check size is valid
-        if !(pos + 4 <= self.len()) {
+        if pos + 4 > self.len() {
             return Err(SliceReadError::UnexpectedEndOfInput);
         }
 
@@ -34,7 +34,7 @@ impl<'a> SliceRead for &'a [u8] {
 
     fn read_be_u64(&self, pos: usize) -> SliceReadResult<u64> {
         // check size is valid
-        if !(pos + 8 <= self.len()) {
+        if pos + 8 > self.len() {
             return Err(SliceReadError::UnexpectedEndOfInput);
         }
 
@@ -60,7 +60,7 @@ impl<'a> SliceRead for &'a [u8] {
     }
 
     fn subslice(&self, start: usize, end: usize) -> SliceReadResult<&[u8]> {
-        if !(end < self.len()) {
+        if end >= self.len() {
             return Err(SliceReadError::UnexpectedEndOfInput);
         }
 
@@ -72,6 +72,7 @@ impl<'a> SliceRead for &'a [u8] {
 pub enum VecWriteError {
     NonContiguousWrite,
     UnalignedWrite,
+    BufferFull,
 }
 
 pub type VecWriteResult = Result<(), VecWriteError>;
@@ -80,7 +81,11 @@ pub trait VecWrite {
     fn write_be_u32(&mut self, pos: usize, val: u32) -> VecWriteResult;
     fn write_be_u64(&mut self, pos: usize, val: u64) -> VecWriteResult;
     fn write_bstring0(&mut self, val: &str) -> VecWriteResult;
+    fn write_bytes(&mut self, val: &[u8]) -> VecWriteResult;
     fn pad(&mut self, alignment: usize) -> VecWriteResult;
+
+    /// The number of bytes written so far.
+    fn offset(&self) -> usize;
 }
 
 impl VecWrite for Vec<u8> {
@@ -136,6 +141,11 @@ impl VecWrite for Vec<u8> {
         Ok(())
     }
 
+    fn write_bytes(&mut self, val: &[u8]) -> VecWriteResult {
+        self.extend_from_slice(val);
+        Ok(())
+    }
+
     fn pad(&mut self, alignment: usize) -> VecWriteResult {
         let misalignment = self.len() % alignment;
         if misalignment > 0 {
@@ -145,4 +155,120 @@ impl VecWrite for Vec<u8> {
         }
         Ok(())
     }
+
+    fn offset(&self) -> usize {
+        self.len()
+    }
+}
+
+/// Writes into a fixed, caller-provided buffer instead of a `Vec`.
+pub struct SliceWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> SliceWriter<'a> {
+    pub fn new(buf: &'a mut [u8]) -> SliceWriter<'a> {
+        SliceWriter { buf, len: 0 }
+    }
+
+    /// Get `size` bytes at `pos`, which may overwrite earlier data but must
+    /// not leave a gap.
+    fn reserve(&mut self, pos: usize, size: usize) -> Result<&mut [u8], VecWriteError> {
+        if pos > self.len {
+            return Err(VecWriteError::NonContiguousWrite);
+        }
+        if pos + size > self.buf.len() {
+            return Err(VecWriteError::BufferFull);
+        }
+        self.len = self.len.max(pos + size);
+        Ok(&mut self.buf[pos..(pos + size)])
+    }
+}
+
+impl<'a> VecWrite for SliceWriter<'a> {
+    fn write_be_u32(&mut self, pos: usize, val: u32) -> VecWriteResult {
+        if pos % 4 != 0 {
+            return Err(VecWriteError::UnalignedWrite);
+        }
+        self.reserve(pos, 4)?.copy_from_slice(&val.to_be_bytes());
+        Ok(())
+    }
+
+    fn write_be_u64(&mut self, pos: usize, val: u64) -> VecWriteResult {
+        if pos % 8 != 0 {
+            return Err(VecWriteError::UnalignedWrite);
+        }
+        self.reserve(pos, 8)?.copy_from_slice(&val.to_be_bytes());
+        Ok(())
+    }
+
+    fn write_bstring0(&mut self, val: &str) -> VecWriteResult {
+        self.write_bytes(val.as_bytes())?;
+        self.write_bytes(&[0])
+    }
+
+    fn write_bytes(&mut self, val: &[u8]) -> VecWriteResult {
+        let pos = self.len;
+        self.reserve(pos, val.len())?.copy_from_slice(val);
+        Ok(())
+    }
+
+    fn pad(&mut self, alignment: usize) -> VecWriteResult {
+        let pos = self.len;
+        let padding = align(pos, alignment) - pos;
+        for b in self.reserve(pos, padding)?.iter_mut() {
+            *b = 0;
+        }
+        Ok(())
+    }
+
+    fn offset(&self) -> usize {
+        self.len
+    }
+}
+
+/// Counts the bytes that would be written without storing them.
+#[derive(Default)]
+pub struct SizeCounter {
+    len: usize,
+}
+
+impl SizeCounter {
+    fn reserve(&mut self, pos: usize, size: usize) -> VecWriteResult {
+        if pos > self.len {
+            return Err(VecWriteError::NonContiguousWrite);
+        }
+        self.len = self.len.max(pos + size);
+        Ok(())
+    }
+}
+
+impl VecWrite for SizeCounter {
+    fn write_be_u32(&mut self, pos: usize, _: u32) -> VecWriteResult {
+        self.reserve(pos, 4)
+    }
+
+    fn write_be_u64(&mut self, pos: usize, _: u64) -> VecWriteResult {
+        self.reserve(pos, 8)
+    }
+
+    fn write_bstring0(&mut self, val: &str) -> VecWriteResult {
+        self.len += val.len() + 1;
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, val: &[u8]) -> VecWriteResult {
+        self.len += val.len();
+        Ok(())
+    }
+
+    fn pad(&mut self, alignment: usize) -> VecWriteResult {
+        self.len = align(self.len, alignment);
+        Ok(())
+    }
+
+    fn offset(&self) -> usize {
+        self.len
+    }
 }
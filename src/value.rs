@@ -0,0 +1,477 @@
+//! Typed property values
+//!
+//! Property values are stored as plain bytes. `PropValue` gives them a type,
+//! either from the schema of well-known properties (`DeviceTree::prop_value`)
+//! or by guessing from the bytes alone like `dtc` does when decompiling
+//! (`PropValue::guess`). Values can be printed in `.dts` syntax and encoded
+//! back to bytes with `PropValue::encode`.
+
+use core::fmt;
+
+use util::SliceReadError;
+use {bytes_to_cells, push_cells, u64_to_cells, DeviceTree, Node, PropError};
+
+/// An error encountered while decoding a property by its schema.
+#[derive(Debug)]
+pub enum ValueError {
+    /// The property could not be read, or did not have the size the schema
+    /// requires.
+    PropError(PropError),
+
+    /// No interrupt parent with `#interrupt-cells` was found to decode
+    /// `interrupts`.
+    NoInterruptParent,
+
+    /// A list of entries ended in the middle of an entry.
+    Truncated,
+}
+
+/// A decoded property value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PropValue {
+    /// A property without value, such as `interrupt-controller`.
+    Empty,
+
+    /// A single 32 bit cell.
+    U32(u32),
+
+    /// A single 64 bit number, stored as two cells.
+    U64(u64),
+
+    /// A list of 32 bit cells.
+    Cells(Vec<u32>),
+
+    /// A single string.
+    String(String),
+
+    /// A list of strings, such as `compatible`.
+    StringList(Vec<String>),
+
+    /// Raw bytes that fit no other type.
+    Bytes(Vec<u8>),
+
+    /// A reference to the node with this phandle, such as
+    /// `interrupt-parent`.
+    PhandleRef(u32),
+
+    /// `(address, size)` pairs encoded with the given number of cells, such
+    /// as `reg`.
+    Reg {
+        address_cells: u32,
+        size_cells: u32,
+        regions: Vec<(u64, u64)>,
+    },
+
+    /// Interrupt specifiers in the format of the interrupt parent.
+    Interrupts(Vec<Vec<u32>>),
+}
+
+impl From<PropError> for ValueError {
+    fn from(e: PropError) -> ValueError {
+        ValueError::PropError(e)
+    }
+}
+
+impl PropValue {
+    /// Guess the type of a value from its bytes, like `dtc` does when
+    /// decompiling: non-empty printable strings, then cells if the length is
+    /// a multiple of 4, then bytes.
+    pub fn guess(raw: &[u8]) -> PropValue {
+        if raw.is_empty() {
+            return PropValue::Empty;
+        }
+
+        if let Some(mut strings) = printable_strings(raw) {
+            if strings.len() == 1 {
+                return PropValue::String(strings.remove(0));
+            }
+            return PropValue::StringList(strings);
+        }
+
+        if let Ok(cells) = bytes_to_cells(raw) {
+            if cells.len() == 1 {
+                return PropValue::U32(cells[0]);
+            }
+            return PropValue::Cells(cells);
+        }
+
+        PropValue::Bytes(raw.to_vec())
+    }
+
+    /// Encode the value as stored in the tree.
+    ///
+    /// Fails only if a `Reg` address or size does not fit its cells.
+    pub fn encode(&self) -> Result<Vec<u8>, PropError> {
+        let mut raw = Vec::new();
+        match *self {
+            PropValue::Empty => (),
+            PropValue::U32(val) | PropValue::PhandleRef(val) => push_cells(&mut raw, &[val]),
+            PropValue::U64(val) => push_cells(&mut raw, &[(val >> 32) as u32, val as u32]),
+            PropValue::Cells(ref cells) => push_cells(&mut raw, cells),
+            PropValue::String(ref val) => {
+                raw.extend_from_slice(val.as_bytes());
+                raw.push(0);
+            }
+            PropValue::StringList(ref vals) => {
+                for val in vals.iter() {
+                    raw.extend_from_slice(val.as_bytes());
+                    raw.push(0);
+                }
+            }
+            PropValue::Bytes(ref bytes) => raw.extend_from_slice(bytes),
+            PropValue::Reg {
+                address_cells,
+                size_cells,
+                ref regions,
+            } => {
+                for &(address, size) in regions.iter() {
+                    push_cells(&mut raw, &u64_to_cells(address, address_cells)?);
+                    push_cells(&mut raw, &u64_to_cells(size, size_cells)?);
+                }
+            }
+            PropValue::Interrupts(ref specifiers) => {
+                for specifier in specifiers.iter() {
+                    push_cells(&mut raw, specifier);
+                }
+            }
+        }
+        Ok(raw)
+    }
+}
+
+/// Prints the value as the right-hand side of a `.dts` property assignment,
+/// e.g. `<0x1 0x2>` or `"a", "b"`. `Empty` prints as nothing.
+///
+/// `Reg` regions whose address or size does not fit the cells are marked
+/// with `/bits/ 64` and printed as one 64 bit number each, which `encode()`
+/// rejects.
+impl fmt::Display for PropValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PropValue::Empty => Ok(()),
+            PropValue::U32(val) => write!(f, "<{:#x}>", val),
+            PropValue::U64(val) => write!(f, "/bits/ 64 <{:#x}>", val),
+            PropValue::Cells(ref cells) => write_cells(f, cells),
+            PropValue::String(ref val) => write_string(f, val),
+            PropValue::StringList(ref vals) => {
+                for (i, val) in vals.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write_string(f, val)?;
+                }
+                Ok(())
+            }
+            PropValue::Bytes(ref bytes) => {
+                f.write_str("[")?;
+                for (i, b) in bytes.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(" ")?;
+                    }
+                    write!(f, "{:02x}", b)?;
+                }
+                f.write_str("]")
+            }
+            PropValue::PhandleRef(val) => write!(f, "<{:#x}>", val),
+            PropValue::Reg {
+                address_cells,
+                size_cells,
+                ref regions,
+            } => {
+                for (i, &(address, size)) in regions.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    match (
+                        u64_to_cells(address, address_cells),
+                        u64_to_cells(size, size_cells),
+                    ) {
+                        (Ok(mut cells), Ok(size)) => {
+                            cells.extend(size);
+                            write_cells(f, &cells)?;
+                        }
+                        _ => write!(f, "/bits/ 64 <{:#x} {:#x}>", address, size)?,
+                    }
+                }
+                Ok(())
+            }
+            PropValue::Interrupts(ref specifiers) => {
+                for (i, specifier) in specifiers.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write_cells(f, specifier)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl DeviceTree {
+    /// Decode property `name` of `node`, which must be a node inside this
+    /// tree, by the schema of well-known properties:
+    ///
+    /// * `compatible` and `*-names` are string lists, `model`, `status` and
+    ///   `device_type` strings,
+    /// * `phandle`, `linux,phandle` and `#*-cells` are single cells,
+    /// * `interrupt-parent` is a phandle reference,
+    /// * `reg` is decoded with the `#address-cells` and `#size-cells` of the
+    ///   parent,
+    /// * `interrupts` is split by the `#interrupt-cells` of the interrupt
+    ///   parent.
+    ///
+    /// Other properties are decoded with `PropValue::guess`. Fails if the
+    /// property is missing or does not match its schema.
+    pub fn prop_value(&self, node: &Node, name: &str) -> Result<PropValue, ValueError> {
+        let raw = node.prop_raw(name).ok_or(PropError::NotFound)?;
+
+        let value = match name {
+            "compatible" => string_list(node.prop_str_list(name)?),
+            _ if name.ends_with("-names") => string_list(node.prop_str_list(name)?),
+            "model" | "status" | "device_type" => {
+                PropValue::String(node.prop_str(name)?.to_owned())
+            }
+            "phandle" | "linux,phandle" => PropValue::U32(single_cell(raw)?),
+            _ if name.starts_with('#') && name.ends_with("-cells") => {
+                PropValue::U32(single_cell(raw)?)
+            }
+            "interrupt-parent" => PropValue::PhandleRef(single_cell(raw)?),
+            "reg" => {
+                let (address_cells, size_cells) = match self.parent_of(node) {
+                    Some(parent) => (parent.address_cells(), parent.size_cells()),
+                    None => (2, 1),
+                };
+                PropValue::Reg {
+                    address_cells,
+                    size_cells,
+                    regions: node.prop_reg(name, address_cells, size_cells)?,
+                }
+            }
+            "interrupts" => {
+                let size = self
+                    .interrupt_parent(node)
+                    .and_then(|parent| parent.prop_u32("#interrupt-cells").ok())
+                    .ok_or(ValueError::NoInterruptParent)? as usize;
+                let cells = node.prop_cells(name)?;
+                if size == 0 || cells.len() % size != 0 {
+                    return Err(ValueError::Truncated);
+                }
+                PropValue::Interrupts(cells.chunks(size).map(|s| s.to_vec()).collect())
+            }
+            _ => PropValue::guess(raw),
+        };
+        Ok(value)
+    }
+}
+
+impl Node {
+    /// Set property `name` to the encoded `value`, see `set_prop`.
+    pub fn set_prop_value(&mut self, name: &str, value: &PropValue) -> Result<(), PropError> {
+        let raw = value.encode()?;
+        self.set_prop(name, raw);
+        Ok(())
+    }
+}
+
+/// Split `raw` into strings if it consists of NUL-terminated, non-empty
+/// strings of printable ASCII characters.
+fn printable_strings(raw: &[u8]) -> Option<Vec<String>> {
+    let (&last, body) = raw.split_last()?;
+    if last != 0 {
+        return None;
+    }
+
+    let mut strings = Vec::new();
+    for s in body.split(|&b| b == 0) {
+        let printable = s
+            .iter()
+            .all(|&b| b.is_ascii_graphic() || b.is_ascii_whitespace());
+        if s.is_empty() || !printable {
+            return None;
+        }
+        strings.push(String::from_utf8(s.to_vec()).ok()?);
+    }
+    Some(strings)
+}
+
+fn string_list(strings: Vec<&str>) -> PropValue {
+    PropValue::StringList(strings.into_iter().map(|s| s.to_owned()).collect())
+}
+
+fn single_cell(raw: &[u8]) -> Result<u32, PropError> {
+    if raw.len() != 4 {
+        return Err(PropError::SliceReadError(
+            SliceReadError::UnexpectedEndOfInput,
+        ));
+    }
+    Ok(bytes_to_cells(raw)?[0])
+}
+
+fn write_cells(f: &mut fmt::Formatter, cells: &[u32]) -> fmt::Result {
+    f.write_str("<")?;
+    for (i, cell) in cells.iter().enumerate() {
+        if i > 0 {
+            f.write_str(" ")?;
+        }
+        write!(f, "{:#x}", cell)?;
+    }
+    f.write_str(">")
+}
+
+fn write_string(f: &mut fmt::Formatter, val: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in val.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\t' => f.write_str("\\t")?,
+            '\r' => f.write_str("\\r")?,
+            _ if c.is_ascii_control() => write!(f, "\\x{:02x}", c as u32)?,
+            _ => write!(f, "{}", c)?,
+        }
+    }
+    f.write_str("\"")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testutil::{cells, node, rpi, tree};
+
+    #[test]
+    fn guess_types() {
+        assert_eq!(PropValue::guess(b""), PropValue::Empty);
+        assert_eq!(
+            PropValue::guess(b"okay\0"),
+            PropValue::String("okay".to_owned())
+        );
+        assert_eq!(
+            PropValue::guess(b"a,b\0c\0"),
+            PropValue::StringList(vec!["a,b".to_owned(), "c".to_owned()])
+        );
+        assert_eq!(PropValue::guess(&cells(&[7])), PropValue::U32(7));
+        assert_eq!(
+            PropValue::guess(&cells(&[1, 0])),
+            PropValue::Cells(vec![1, 0])
+        );
+        // empty strings and control characters are not text
+        assert_eq!(
+            PropValue::guess(b"a\0\0b\0\0\0\0"),
+            PropValue::Cells(vec![0x61000062, 0])
+        );
+        assert_eq!(
+            PropValue::guess(b"\x01\x02\0"),
+            PropValue::Bytes(vec![1, 2, 0])
+        );
+    }
+
+    #[test]
+    fn decode_schema() {
+        let dt = rpi();
+        let uart = dt.find("/soc/uart@7e201000").unwrap();
+
+        assert_eq!(
+            dt.prop_value(uart, "compatible").unwrap(),
+            PropValue::StringList(vec!["arm,pl011".to_owned(), "arm,primecell".to_owned()])
+        );
+        assert_eq!(
+            dt.prop_value(uart, "reg").unwrap(),
+            PropValue::Reg {
+                address_cells: 1,
+                size_cells: 1,
+                regions: vec![(0x7e201000, 0x1000)],
+            }
+        );
+        assert_eq!(
+            dt.prop_value(uart, "interrupts").unwrap(),
+            PropValue::Interrupts(vec![vec![2, 25]])
+        );
+        assert_eq!(
+            dt.prop_value(&dt.root, "interrupt-parent").unwrap(),
+            PropValue::PhandleRef(1)
+        );
+        assert_eq!(
+            dt.prop_value(&dt.root, "#address-cells").unwrap(),
+            PropValue::U32(1)
+        );
+        match dt.prop_value(uart, "missing") {
+            Err(ValueError::PropError(PropError::NotFound)) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn schema_mismatch() {
+        let dt = tree(node(
+            "",
+            vec![("#address-cells", cells(&[1, 2]))],
+            vec![node("dev", vec![("interrupts", cells(&[1]))], vec![])],
+        ));
+        assert!(dt.prop_value(&dt.root, "#address-cells").is_err());
+        match dt.prop_value(dt.find("/dev").unwrap(), "interrupts") {
+            Err(ValueError::NoInterruptParent) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn encode_roundtrip() {
+        let dt = rpi();
+        for (_, node) in dt.nodes() {
+            for (name, raw) in node.props.iter() {
+                assert_eq!(&PropValue::guess(raw).encode().unwrap(), raw);
+                if let Ok(value) = dt.prop_value(node, name) {
+                    assert_eq!(&value.encode().unwrap(), raw, "{}", name);
+                }
+            }
+        }
+
+        let mut dev = node("dev", vec![], vec![]);
+        let reg = PropValue::Reg {
+            address_cells: 2,
+            size_cells: 1,
+            regions: vec![(0x1_0000_0000, 0x100)],
+        };
+        dev.set_prop_value("reg", &reg).unwrap();
+        assert_eq!(dev.prop_cells("reg").unwrap(), vec![1, 0, 0x100]);
+        dev.set_prop_value("wide", &PropValue::U64(1 << 32))
+            .unwrap();
+        assert_eq!(dev.prop_u64("wide").unwrap(), 1 << 32);
+
+        let too_big = PropValue::Reg {
+            address_cells: 1,
+            size_cells: 1,
+            regions: vec![(0x1_0000_0000, 0)],
+        };
+        assert!(dev.set_prop_value("reg", &too_big).is_err());
+    }
+
+    #[test]
+    fn dts_syntax() {
+        let reg = PropValue::Reg {
+            address_cells: 1,
+            size_cells: 1,
+            regions: vec![(0x1000, 0x20), (0x2000, 0x20)],
+        };
+        assert_eq!(reg.to_string(), "<0x1000 0x20>, <0x2000 0x20>");
+        let too_big = PropValue::Reg {
+            address_cells: 1,
+            size_cells: 1,
+            regions: vec![(0x1_0000_0000, 0)],
+        };
+        assert_eq!(too_big.to_string(), "/bits/ 64 <0x100000000 0x0>");
+        assert_eq!(
+            PropValue::StringList(vec!["a".to_owned(), "say \"b\"".to_owned()]).to_string(),
+            r#""a", "say \"b\"""#
+        );
+        assert_eq!(
+            PropValue::String("bell\x07".to_owned()).to_string(),
+            r#""bell\x07""#
+        );
+        assert_eq!(PropValue::Bytes(vec![0xab, 1]).to_string(), "[ab 01]");
+        assert_eq!(PropValue::U64(1).to_string(), "/bits/ 64 <0x1>");
+        assert_eq!(PropValue::Empty.to_string(), "");
+    }
+}
@@ -0,0 +1,293 @@
+//! Tree traversal
+//!
+//! Iterators over a node and all its descendants in pre-order, post-order
+//! and breadth-first order, a mutable walker and a `Visitor` trait with
+//! callbacks for entering and leaving nodes and for each property.
+//!
+//! Everything is available on any `Node`, so subtrees can be traversed just
+//! like the whole `DeviceTree`.
+
+use std::collections::VecDeque;
+
+use {DeviceTree, Node};
+
+/// Returned by `Visitor::enter` to control the traversal.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Walk {
+    /// Visit the properties and children of the node.
+    Continue,
+
+    /// Skip the properties and children of the node. `leave` is not called
+    /// for it either.
+    Skip,
+
+    /// Stop the traversal altogether.
+    Stop,
+}
+
+/// Callbacks for a depth-first traversal, see `Node::accept`.
+///
+/// For every node, `enter` is called first, then `property` for each of its
+/// properties, then the children are visited and finally `leave` is called.
+pub trait Visitor<'a> {
+    fn enter(&mut self, _depth: usize, _node: &'a Node) -> Walk {
+        Walk::Continue
+    }
+
+    fn property(&mut self, _node: &'a Node, _name: &'a str, _value: &'a [u8]) {}
+
+    fn leave(&mut self, _depth: usize, _node: &'a Node) {}
+}
+
+/// Pre-order iterator yielding `(depth, node)`, see `Node::pre_order`.
+pub struct PreOrder<'a> {
+    stack: Vec<(usize, &'a Node)>,
+}
+
+/// Post-order iterator yielding `(depth, node)`, see `Node::post_order`.
+pub struct PostOrder<'a> {
+    stack: Vec<(&'a Node, usize)>,
+}
+
+/// Breadth-first iterator yielding `(depth, node)`, see
+/// `Node::breadth_first`.
+pub struct BreadthFirst<'a> {
+    queue: VecDeque<(usize, &'a Node)>,
+}
+
+/// Pre-order iterator yielding `(path, node)`, see `Node::paths`.
+pub struct Paths<'a> {
+    stack: Vec<(String, &'a Node)>,
+}
+
+impl<'a> Iterator for PreOrder<'a> {
+    type Item = (usize, &'a Node);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (depth, node) = self.stack.pop()?;
+        self.stack
+            .extend(node.children.iter().rev().map(|child| (depth + 1, child)));
+        Some((depth, node))
+    }
+}
+
+impl<'a> Iterator for PostOrder<'a> {
+    type Item = (usize, &'a Node);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (node, idx) = {
+                let top = self.stack.last_mut()?;
+                top.1 += 1;
+                (top.0, top.1 - 1)
+            };
+
+            if idx < node.children.len() {
+                self.stack.push((&node.children[idx], 0));
+            } else {
+                self.stack.pop();
+                return Some((self.stack.len(), node));
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for BreadthFirst<'a> {
+    type Item = (usize, &'a Node);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (depth, node) = self.queue.pop_front()?;
+        self.queue
+            .extend(node.children.iter().map(|child| (depth + 1, child)));
+        Some((depth, node))
+    }
+}
+
+impl<'a> Iterator for Paths<'a> {
+    type Item = (String, &'a Node);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (path, node) = self.stack.pop()?;
+        for child in node.children.iter().rev() {
+            let child_path = if path.ends_with('/') {
+                format!("{}{}", path, child.name)
+            } else {
+                format!("{}/{}", path, child.name)
+            };
+            self.stack.push((child_path, child));
+        }
+        Some((path, node))
+    }
+}
+
+impl Node {
+    /// Iterate over this node and its descendants, parents before children.
+    /// The node itself has depth 0.
+    pub fn pre_order(&self) -> PreOrder<'_> {
+        PreOrder {
+            stack: vec![(0, self)],
+        }
+    }
+
+    /// Iterate over this node and its descendants, children before parents.
+    pub fn post_order(&self) -> PostOrder<'_> {
+        PostOrder {
+            stack: vec![(self, 0)],
+        }
+    }
+
+    /// Iterate over this node and its descendants level by level.
+    pub fn breadth_first(&self) -> BreadthFirst<'_> {
+        let mut queue = VecDeque::new();
+        queue.push_back((0, self));
+        BreadthFirst { queue }
+    }
+
+    /// Iterate over this node and its descendants in pre-order, along with
+    /// their paths. `path` is the path of this node.
+    pub fn paths(&self, path: &str) -> Paths<'_> {
+        Paths {
+            stack: vec![(path.to_owned(), self)],
+        }
+    }
+
+    /// Call `f` with the depth and a mutable reference for this node and
+    /// each of its descendants, in pre-order.
+    pub fn walk_mut<F>(&mut self, f: &mut F)
+    where
+        F: FnMut(usize, &mut Node),
+    {
+        self.walk_mut_at(0, f);
+    }
+
+    fn walk_mut_at<F>(&mut self, depth: usize, f: &mut F)
+    where
+        F: FnMut(usize, &mut Node),
+    {
+        f(depth, self);
+        for child in self.children.iter_mut() {
+            child.walk_mut_at(depth + 1, f);
+        }
+    }
+
+    /// Traverse this node and its descendants with `visitor`.
+    ///
+    /// Returns `Walk::Stop` if the visitor stopped the traversal.
+    pub fn accept<'a, V: Visitor<'a>>(&'a self, visitor: &mut V) -> Walk {
+        self.accept_at(0, visitor)
+    }
+
+    fn accept_at<'a, V: Visitor<'a>>(&'a self, depth: usize, visitor: &mut V) -> Walk {
+        match visitor.enter(depth, self) {
+            Walk::Continue => (),
+            other => return other,
+        }
+
+        for (name, value) in self.props.iter() {
+            visitor.property(self, name, value);
+        }
+
+        for child in self.children.iter() {
+            if child.accept_at(depth + 1, visitor) == Walk::Stop {
+                return Walk::Stop;
+            }
+        }
+
+        visitor.leave(depth, self);
+        Walk::Continue
+    }
+}
+
+impl DeviceTree {
+    /// Iterate over all nodes in pre-order, see `Node::pre_order`.
+    pub fn nodes(&self) -> PreOrder<'_> {
+        self.root.pre_order()
+    }
+
+    /// Iterate over all nodes in pre-order along with their absolute paths.
+    pub fn paths(&self) -> Paths<'_> {
+        self.root.paths("/")
+    }
+
+    /// Call `f` for every node in pre-order, see `Node::walk_mut`.
+    pub fn walk_mut<F>(&mut self, f: &mut F)
+    where
+        F: FnMut(usize, &mut Node),
+    {
+        self.root.walk_mut(f)
+    }
+
+    /// Traverse the whole tree with `visitor`, see `Node::accept`.
+    pub fn accept<'a, V: Visitor<'a>>(&'a self, visitor: &mut V) -> Walk {
+        self.root.accept(visitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testutil::rpi;
+
+    struct Counter {
+        nodes: usize,
+        props: usize,
+        left: usize,
+    }
+
+    impl<'a> Visitor<'a> for Counter {
+        fn enter(&mut self, _depth: usize, node: &'a Node) -> Walk {
+            if node.name == "__overrides__" || node.name == "__symbols__" {
+                return Walk::Skip;
+            }
+            self.nodes += 1;
+            Walk::Continue
+        }
+
+        fn property(&mut self, _node: &'a Node, _name: &'a str, _value: &'a [u8]) {
+            self.props += 1;
+        }
+
+        fn leave(&mut self, _depth: usize, _node: &'a Node) {
+            self.left += 1;
+        }
+    }
+
+    #[test]
+    fn orders() {
+        let dt = rpi();
+        let total = dt.nodes().count();
+
+        assert_eq!(dt.root.post_order().count(), total);
+        assert_eq!(dt.root.breadth_first().count(), total);
+
+        let (depth, last) = dt.root.post_order().last().unwrap();
+        assert_eq!(depth, 0);
+        assert!(last.name.is_empty());
+
+        let depths: Vec<usize> = dt.root.breadth_first().map(|(d, _)| d).collect();
+        assert!(depths.windows(2).all(|w| w[0] <= w[1]));
+
+        let paths: Vec<String> = dt.paths().map(|(p, _)| p).collect();
+        assert_eq!(paths[0], "/");
+        assert_eq!(paths[1], "/chosen");
+        assert!(paths.contains(&"/soc/gpio@7e200000/i2c0".to_owned()));
+    }
+
+    #[test]
+    fn visitor_and_walk_mut() {
+        let mut dt = rpi();
+        let mut counter = Counter {
+            nodes: 0,
+            props: 0,
+            left: 0,
+        };
+
+        assert_eq!(dt.accept(&mut counter), Walk::Continue);
+        assert_eq!(counter.nodes, dt.nodes().count() - 2);
+        assert_eq!(counter.nodes, counter.left);
+        assert!(counter.props > 0);
+
+        dt.walk_mut(&mut |_, node| node.props.clear());
+        assert!(dt.nodes().all(|(_, n)| n.props.is_empty()));
+    }
+}
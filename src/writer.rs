@@ -0,0 +1,458 @@
+//! Sequential construction of device tree blobs
+//!
+//! `FdtWriter` emits a blob while the tree is being described, like libfdt's
+//! `fdt_create()`, `fdt_begin_node()`, `fdt_property()`, `fdt_end_node()` and
+//! `fdt_finish()`, without building a `DeviceTree` first. The structure
+//! block goes straight to the sink; only the property names are kept in
+//! memory until `finish()` appends the strings block.
+//!
+//! The sizes and offsets in the header are only known at the end, so
+//! `finish()` fills them in. Sinks therefore need to be able to overwrite
+//! the header: every `VecWrite` implementation is a sink, and `IoSink` adapts
+//! any seekable `std::io::Write`.
+//!
+//! Output that cannot seek, such as a pipe or a socket, is not streamed. The
+//! header comes first in the blob but is only complete at the end, so
+//! `BufferedSink` holds the whole blob in memory and writes it out in
+//! `finish()`.
+
+use std::io;
+
+use util::{VecWrite, VecWriteError};
+use {
+    COMPAT_VERSION, MAGIC_NUMBER, OF_DT_BEGIN_NODE, OF_DT_END, OF_DT_END_NODE, OF_DT_PROP,
+    SUPPORTED_VERSION,
+};
+
+/// A destination for `FdtWriter`.
+pub trait Sink {
+    type Error;
+
+    /// Append `data`.
+    fn write(&mut self, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Overwrite the four bytes at `pos`, which have been written before.
+    fn patch_u32(&mut self, pos: usize, val: u32) -> Result<(), Self::Error>;
+
+    /// Called by `FdtWriter::finish()` once the blob is complete.
+    fn complete(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl<T: VecWrite> Sink for T {
+    type Error = VecWriteError;
+
+    fn write(&mut self, data: &[u8]) -> Result<(), VecWriteError> {
+        self.write_bytes(data)
+    }
+
+    fn patch_u32(&mut self, pos: usize, val: u32) -> Result<(), VecWriteError> {
+        self.write_be_u32(pos, val)
+    }
+}
+
+/// Adapts a seekable `std::io::Write`, e.g. a `File`, for `FdtWriter`.
+///
+/// The blob starts at the position of the writer when it is wrapped.
+pub struct IoSink<W> {
+    inner: W,
+    start: u64,
+}
+
+impl<W: io::Write + io::Seek> IoSink<W> {
+    pub fn new(mut inner: W) -> io::Result<IoSink<W>> {
+        let start = inner.stream_position()?;
+        Ok(IoSink { inner, start })
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: io::Write + io::Seek> Sink for IoSink<W> {
+    type Error = io::Error;
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.inner.write_all(data)
+    }
+
+    fn patch_u32(&mut self, pos: usize, val: u32) -> io::Result<()> {
+        let end = self.inner.stream_position()?;
+        self.inner
+            .seek(io::SeekFrom::Start(self.start + pos as u64))?;
+        self.inner.write_all(&val.to_be_bytes())?;
+        self.inner.seek(io::SeekFrom::Start(end))?;
+        Ok(())
+    }
+}
+
+/// Adapts any `std::io::Write`, including ones that cannot seek, without
+/// streaming.
+///
+/// Nothing reaches the inner writer before `FdtWriter::finish()`: the whole
+/// blob is collected in memory, since the header can only be filled in at
+/// the end. Use `IoSink` to stream to a seekable writer.
+pub struct BufferedSink<W> {
+    inner: W,
+    buf: Vec<u8>,
+}
+
+impl<W: io::Write> BufferedSink<W> {
+    pub fn new(inner: W) -> BufferedSink<W> {
+        BufferedSink {
+            inner,
+            buf: Vec::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: io::Write> Sink for BufferedSink<W> {
+    type Error = io::Error;
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.buf.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn patch_u32(&mut self, pos: usize, val: u32) -> io::Result<()> {
+        self.buf[pos..(pos + 4)].copy_from_slice(&val.to_be_bytes());
+        Ok(())
+    }
+
+    fn complete(&mut self) -> io::Result<()> {
+        self.inner.write_all(&self.buf)?;
+        self.buf = Vec::new();
+        self.inner.flush()
+    }
+}
+
+/// An error encountered while writing a blob sequentially.
+#[derive(Debug)]
+pub enum WriterError<E> {
+    /// The sink failed.
+    SinkError(E),
+
+    /// The call is not allowed at this point, e.g. a reservation after the
+    /// first node, a property after a child node, or `finish()` while nodes
+    /// are still open.
+    BadState,
+}
+
+impl<E> From<E> for WriterError<E> {
+    fn from(e: E) -> WriterError<E> {
+        WriterError::SinkError(e)
+    }
+}
+
+/// Writes a device tree blob sequentially, see the module documentation.
+pub struct FdtWriter<S: Sink> {
+    sink: S,
+
+    /// The number of bytes written so far.
+    offset: usize,
+
+    /// The start of the structure block, once the first node was begun.
+    struct_start: Option<usize>,
+
+    /// The number of open nodes.
+    depth: usize,
+
+    /// Properties can still be added to the innermost open node.
+    props_allowed: bool,
+
+    boot_cpuid_phys: u32,
+
+    strings: Vec<u8>,
+
+    /// The offsets of the names in `strings`, sorted by name.
+    names: Vec<u32>,
+}
+
+type WriterResult<T, S> = Result<T, WriterError<<S as Sink>::Error>>;
+
+impl<S: Sink> FdtWriter<S> {
+    /// Start a blob by writing its header to `sink`.
+    pub fn new(sink: S) -> WriterResult<FdtWriter<S>, S> {
+        let mut writer = FdtWriter {
+            sink,
+            offset: 0,
+            struct_start: None,
+            depth: 0,
+            props_allowed: false,
+            boot_cpuid_phys: 0,
+            strings: Vec::new(),
+            names: Vec::new(),
+        };
+
+        // magic, totalsize, off_dt_struct, off_dt_strings, off_mem_rsvmap,
+        // version, last_comp_version, boot_cpuid_phys, size_dt_strings,
+        // size_dt_struct
+        let header = [
+            MAGIC_NUMBER,
+            0,
+            0,
+            0,
+            40,
+            SUPPORTED_VERSION,
+            COMPAT_VERSION,
+            0,
+            0,
+            0,
+        ];
+        for field in header.iter() {
+            writer.write_u32(*field)?;
+        }
+        Ok(writer)
+    }
+
+    /// Set the `boot_cpuid_phys` header field, which defaults to 0.
+    pub fn set_boot_cpuid_phys(&mut self, cpu: u32) {
+        self.boot_cpuid_phys = cpu;
+    }
+
+    /// Add a memory reservation. All reservations have to be added before
+    /// the root node.
+    pub fn add_reservation(&mut self, address: u64, size: u64) -> WriterResult<(), S> {
+        if self.struct_start.is_some() {
+            return Err(WriterError::BadState);
+        }
+        self.write_bytes(&address.to_be_bytes())?;
+        self.write_bytes(&size.to_be_bytes())
+    }
+
+    /// Open a node named `name`, which becomes the parent of the following
+    /// nodes and properties until `end_node()`. The root node has an empty
+    /// name.
+    pub fn begin_node(&mut self, name: &str) -> WriterResult<(), S> {
+        match self.struct_start {
+            Some(_) if self.depth == 0 => return Err(WriterError::BadState),
+            Some(_) => (),
+            None => {
+                // terminate the memory reservation block
+                self.write_bytes(&[0; 16])?;
+                self.struct_start = Some(self.offset);
+            }
+        }
+
+        self.write_u32(OF_DT_BEGIN_NODE)?;
+        self.write_bytes(name.as_bytes())?;
+        self.write_bytes(&[0])?;
+        self.pad()?;
+        self.depth += 1;
+        self.props_allowed = true;
+        Ok(())
+    }
+
+    /// Add a property to the current node. Properties have to come before
+    /// the child nodes.
+    pub fn property(&mut self, name: &str, value: &[u8]) -> WriterResult<(), S> {
+        if !self.props_allowed {
+            return Err(WriterError::BadState);
+        }
+
+        let name_offset = self.add_string(name);
+        self.write_u32(OF_DT_PROP)?;
+        self.write_u32(value.len() as u32)?;
+        self.write_u32(name_offset)?;
+        self.write_bytes(value)?;
+        self.pad()
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32) -> WriterResult<(), S> {
+        self.property(name, &value.to_be_bytes())
+    }
+
+    pub fn property_u64(&mut self, name: &str, value: u64) -> WriterResult<(), S> {
+        self.property(name, &value.to_be_bytes())
+    }
+
+    /// Add a property holding a null-terminated string.
+    pub fn property_str(&mut self, name: &str, value: &str) -> WriterResult<(), S> {
+        let mut bytes = Vec::with_capacity(value.len() + 1);
+        bytes.extend_from_slice(value.as_bytes());
+        bytes.push(0);
+        self.property(name, &bytes)
+    }
+
+    /// Add a property holding a list of cells.
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) -> WriterResult<(), S> {
+        let mut bytes = Vec::with_capacity(cells.len() * 4);
+        for cell in cells.iter() {
+            bytes.extend_from_slice(&cell.to_be_bytes());
+        }
+        self.property(name, &bytes)
+    }
+
+    /// Close the current node.
+    pub fn end_node(&mut self) -> WriterResult<(), S> {
+        if self.depth == 0 {
+            return Err(WriterError::BadState);
+        }
+        self.write_u32(OF_DT_END_NODE)?;
+        self.depth -= 1;
+        self.props_allowed = false;
+        Ok(())
+    }
+
+    /// Complete the blob after the root node was closed, returning the sink
+    /// and the size of the blob.
+    pub fn finish(mut self) -> WriterResult<(S, usize), S> {
+        let struct_start = match self.struct_start {
+            Some(start) if self.depth == 0 => start,
+            _ => return Err(WriterError::BadState),
+        };
+        self.write_u32(OF_DT_END)?;
+        let struct_size = self.offset - struct_start;
+
+        let strings = ::core::mem::take(&mut self.strings);
+        let strings_start = self.offset;
+        self.write_bytes(&strings)?;
+
+        let total = self.offset;
+        self.sink.patch_u32(4, total as u32)?;
+        self.sink.patch_u32(8, struct_start as u32)?;
+        self.sink.patch_u32(12, strings_start as u32)?;
+        self.sink.patch_u32(28, self.boot_cpuid_phys)?;
+        self.sink.patch_u32(32, strings.len() as u32)?;
+        self.sink.patch_u32(36, struct_size as u32)?;
+        self.sink.complete()?;
+        Ok((self.sink, total))
+    }
+
+    fn write_bytes(&mut self, data: &[u8]) -> WriterResult<(), S> {
+        self.sink.write(data)?;
+        self.offset += data.len();
+        Ok(())
+    }
+
+    fn write_u32(&mut self, val: u32) -> WriterResult<(), S> {
+        self.write_bytes(&val.to_be_bytes())
+    }
+
+    fn pad(&mut self) -> WriterResult<(), S> {
+        let padding = (4 - self.offset % 4) % 4;
+        self.write_bytes(&[0; 4][..padding])
+    }
+
+    fn string_at(&self, offset: u32) -> &[u8] {
+        let rest = &self.strings[offset as usize..];
+        let len = rest.iter().position(|&b| b == 0).unwrap_or(rest.len());
+        &rest[..len]
+    }
+
+    fn add_string(&mut self, name: &str) -> u32 {
+        match self
+            .names
+            .binary_search_by(|&offset| self.string_at(offset).cmp(name.as_bytes()))
+        {
+            Ok(i) => self.names[i],
+            Err(i) => {
+                let offset = self.strings.len() as u32;
+                self.strings.extend_from_slice(name.as_bytes());
+                self.strings.push(0);
+                self.names.insert(i, offset);
+                offset
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testutil::{cells, node, rpi, tree};
+    use {DeviceTree, Node};
+
+    fn write_node<S: Sink>(writer: &mut FdtWriter<S>, node: &Node) -> WriterResult<(), S> {
+        writer.begin_node(&node.name)?;
+        for (name, value) in node.props.iter() {
+            writer.property(name, value)?;
+        }
+        for child in node.children.iter() {
+            write_node(writer, child)?;
+        }
+        writer.end_node()
+    }
+
+    #[test]
+    fn sequential() {
+        let mut writer = FdtWriter::new(Vec::new()).unwrap();
+        writer.set_boot_cpuid_phys(1);
+        writer.add_reservation(0x1000, 0x100).unwrap();
+        writer.begin_node("").unwrap();
+        writer.property_u32("#address-cells", 1).unwrap();
+        writer.begin_node("cpus").unwrap();
+        writer.property_cells("reg", &[0, 1]).unwrap();
+        writer.end_node().unwrap();
+        assert!(matches!(
+            writer.property_u32("#size-cells", 1),
+            Err(WriterError::BadState)
+        ));
+        writer.begin_node("chosen").unwrap();
+        writer.property_str("bootargs", "quiet").unwrap();
+        writer.property_u64("linux,initrd-start", 1).unwrap();
+        writer.end_node().unwrap();
+        writer.end_node().unwrap();
+        assert!(matches!(
+            writer.begin_node("second-root"),
+            Err(WriterError::BadState)
+        ));
+        let (blob, size) = writer.finish().unwrap();
+        assert_eq!(blob.len(), size);
+
+        let mut expected = tree(node(
+            "",
+            vec![("#address-cells", cells(&[1]))],
+            vec![
+                node("cpus", vec![("reg", cells(&[0, 1]))], vec![]),
+                node(
+                    "chosen",
+                    vec![
+                        ("bootargs", b"quiet\0".to_vec()),
+                        ("linux,initrd-start", cells(&[0, 1])),
+                    ],
+                    vec![],
+                ),
+            ],
+        ));
+        expected.boot_cpuid_phys = 1;
+        expected.reserved = vec![(0x1000, 0x100), (0, 0)];
+        assert_eq!(DeviceTree::load(&blob).unwrap(), expected);
+
+        let writer = FdtWriter::new(Vec::new()).unwrap();
+        assert!(matches!(writer.finish(), Err(WriterError::BadState)));
+    }
+
+    #[test]
+    fn stream_to_io() {
+        let dt = rpi();
+        let mut out = io::Cursor::new(vec![0xff; 3]);
+        out.set_position(3);
+
+        let mut writer = FdtWriter::new(IoSink::new(out).unwrap()).unwrap();
+        for &(address, size) in dt.reserved.iter().filter(|r| r.1 != 0) {
+            writer.add_reservation(address, size).unwrap();
+        }
+        write_node(&mut writer, &dt.root).unwrap();
+        let (sink, size) = writer.finish().unwrap();
+
+        let buf = sink.into_inner().into_inner();
+        assert_eq!(buf.len(), size + 3);
+        assert_eq!(DeviceTree::load(&buf[3..]).unwrap(), dt);
+
+        // a writer that cannot seek
+        let mut writer = FdtWriter::new(BufferedSink::new(Vec::new())).unwrap();
+        write_node(&mut writer, &dt.root).unwrap();
+        let (sink, size) = writer.finish().unwrap();
+
+        let buf = sink.into_inner();
+        assert_eq!(buf.len(), size);
+        assert_eq!(DeviceTree::load(&buf).unwrap().root, dt.root);
+    }
+}